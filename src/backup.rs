@@ -0,0 +1,167 @@
+use std::{
+    collections::BTreeMap,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Result};
+use rustygit::Repository;
+use serde::{Deserialize, Serialize};
+
+use crate::{migrations, repo_extensions::RepoExtenstions, state::GsState};
+
+const BACKUP_REFS: &str = "refs/gstack/backup";
+/// Number of backups that are kept, older ones are deleted when a new backup is created
+const MAX_BACKUPS: usize = 30;
+
+/// Snapshot of all stack branch heads and the state, taken before a mutating command
+pub struct Backup {
+    pub id: String,
+    pub command: String,
+    pub created_at: u64,
+    pub state: GsState,
+    pub branch_heads: BTreeMap<String, String>,
+}
+
+/// Stored as a blob next to the branch head refs of a backup. The state is read as a plain
+/// value first, so that backups of older versions go through the state migrations.
+#[derive(Serialize, Deserialize)]
+struct BackupInfo<S> {
+    command: String,
+    created_at: u64,
+    state: S,
+}
+
+/// Stores the heads of all stack branches under `refs/gstack/backup/<id>/heads/` and the
+/// state under `refs/gstack/backup/<id>/state`, returning the id of the new backup
+pub fn create(repo: &Repository, command: &str, state: &GsState) -> Result<String> {
+    let created_at = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let existing = list_ids(repo)?;
+    let mut id = created_at;
+    while existing.contains(&id.to_string()) {
+        id += 1;
+    }
+    let id = id.to_string();
+
+    for branch in state.stacks.iter().flat_map(|stack| &stack.branches) {
+        if let Ok(sha) = repo.head_sha(branch) {
+            let backup_ref = format!("{}/{}/heads/{}", BACKUP_REFS, id, branch);
+            repo.cmd(["update-ref", backup_ref.as_str(), sha.as_str()])?;
+        }
+    }
+    let info = BackupInfo {
+        command: command.to_string(),
+        created_at,
+        state,
+    };
+    let blob = repo.write_blob(&ron::ser::to_string(&info)?)?;
+    let state_ref = format!("{}/{}/state", BACKUP_REFS, id);
+    repo.cmd(["update-ref", state_ref.as_str(), blob.as_str()])?;
+
+    prune(repo)?;
+    Ok(id)
+}
+
+/// All backups, newest first
+pub fn list(repo: &Repository) -> Result<Vec<Backup>> {
+    let mut ids = list_ids(repo)?;
+    ids.sort_by_key(|id| std::cmp::Reverse(id.parse::<u64>().unwrap_or_default()));
+    ids.iter().map(|id| read(repo, id)).collect()
+}
+
+pub fn read(repo: &Repository, id: &str) -> Result<Backup> {
+    let prefix = format!("{}/{}/", BACKUP_REFS, id);
+    let refs = repo.cmd_out([
+        "for-each-ref",
+        "--format=%(refname) %(objectname)",
+        prefix.as_str(),
+    ])?;
+    if refs.is_empty() {
+        bail!("Unknown backup {}", id);
+    }
+
+    let mut branch_heads = BTreeMap::new();
+    let mut info = None;
+    for line in refs {
+        let Some((name, sha)) = line.split_once(' ') else {
+            continue;
+        };
+        let name = name.trim_start_matches(&prefix);
+        if let Some(branch) = name.strip_prefix("heads/") {
+            branch_heads.insert(branch.to_string(), sha.to_string());
+        } else if name == "state" {
+            info = Some(ron::from_str::<BackupInfo<ron::Value>>(
+                &repo.read_blob(sha)?,
+            )?);
+        }
+    }
+    let Some(info) = info else {
+        bail!("Backup {} has no state", id);
+    };
+    let (state, _) = migrations::parse_state(info.state)?;
+    Ok(Backup {
+        id: id.to_string(),
+        command: info.command,
+        created_at: info.created_at,
+        state,
+        branch_heads,
+    })
+}
+
+/// Moves all branches of the backup back to their backed up heads, recreating deleted ones.
+/// Restoring the state is left to the caller.
+pub fn restore_branches(repo: &Repository, backup: &Backup) -> Result<()> {
+    let current_branch = repo.current_branch()?.to_string();
+    let worktrees = repo.worktrees()?;
+    for (branch, sha) in &backup.branch_heads {
+        if *branch == current_branch {
+            repo.cmd(["reset", "--keep", sha.as_str()])?;
+        } else if let Some(path) = worktrees.get(branch) {
+            // Branches checked out in another worktree can't be moved with `git branch -f`
+            Repository::new(path).cmd(["reset", "--keep", sha.as_str()])?;
+        } else {
+            repo.cmd(["branch", "-f", branch.as_str(), sha.as_str()])?;
+        }
+    }
+    Ok(())
+}
+
+fn list_ids(repo: &Repository) -> Result<Vec<String>> {
+    let prefix = format!("{}/", BACKUP_REFS);
+    let refs = repo.cmd_out(["for-each-ref", "--format=%(refname)", prefix.as_str()])?;
+    let mut ids: Vec<String> = refs
+        .iter()
+        .filter_map(|name| name.trim_start_matches(&prefix).split('/').next())
+        .map(|id| id.to_string())
+        .collect();
+    ids.dedup();
+    Ok(ids)
+}
+
+fn prune(repo: &Repository) -> Result<()> {
+    let mut ids = list_ids(repo)?;
+    ids.sort_by_key(|id| std::cmp::Reverse(id.parse::<u64>().unwrap_or_default()));
+    for id in ids.iter().skip(MAX_BACKUPS) {
+        let prefix = format!("{}/{}/", BACKUP_REFS, id);
+        for name in repo.cmd_out(["for-each-ref", "--format=%(refname)", prefix.as_str()])? {
+            repo.cmd(["update-ref", "-d", name.as_str()])?;
+        }
+    }
+    Ok(())
+}
+
+impl Backup {
+    /// Human readable time since the backup was created
+    pub fn age(&self) -> String {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|now| now.as_secs())
+            .unwrap_or(self.created_at);
+        let secs = now.saturating_sub(self.created_at);
+        match secs {
+            0..=59 => format!("{}s ago", secs),
+            60..=3599 => format!("{}m ago", secs / 60),
+            3600..=86399 => format!("{}h ago", secs / 3600),
+            _ => format!("{}d ago", secs / 86400),
+        }
+    }
+}
@@ -1,6 +1,7 @@
 use std::path::PathBuf;
 
-use clap::{command, Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
+use serde::{Deserialize, Serialize};
 
 #[derive(Parser)]
 #[command(version, about)]
@@ -25,12 +26,41 @@ pub enum Commands {
         #[arg(short, long)]
         name: Option<String>,
     },
-    /// Stacks a new branch on top of the current stack
+    /// Stacks a new branch on top of the current branch
     Add {
         /// Name of this change/branch
         #[arg(short, long)]
         name: Option<String>,
     },
+    /// Inserts a new branch into the middle of the stack, restacking the branches above it
+    Insert {
+        /// Name of this change/branch
+        #[arg(short, long)]
+        name: Option<String>,
+
+        /// Stack branch to insert the new branch above, defaults to the current branch
+        #[arg(long, conflicts_with = "before")]
+        after: Option<String>,
+
+        /// Stack branch to insert the new branch below
+        #[arg(long)]
+        before: Option<String>,
+    },
+    /// Add existing branches to the current stack, or to a new stack based on the current branch
+    Adopt {
+        /// Branches to adopt, their parents are inferred from git ancestry
+        branches: Vec<String>,
+
+        /// Infer the parents from the base branches of open PR's, starting at the given branches
+        /// or the current branch
+        #[arg(long)]
+        from_prs: bool,
+    },
+    /// Fetch the stack a PR belongs to, e.g. to review a colleague's stack locally
+    CheckoutPr {
+        /// Number of any PR of the stack
+        number: u64,
+    },
     /// Removes the currently checked out branch from the stack
     Remove {},
     /// List all stacks(not in stack branch) or branches(in stack branch)
@@ -42,6 +72,22 @@ pub enum Commands {
     /// Update, auto-rebase, and push all stack branches to make sure they are in sync
     #[clap(alias = "ss")]
     Sync {},
+    /// Rebase all stack branches onto their parent branch locally, without pulling or pushing
+    Restack {
+        /// Only restack the current branch and the branches above it
+        #[arg(short, long)]
+        upstack: bool,
+    },
+    /// Reorder the branches of the stack in your editor and rebase them onto their new parents
+    Reorder {},
+    /// Move the commits of the current branch into the branch below it and remove it from the stack
+    Fold {},
+    /// Split the current branch into several stacked branches at selected commits
+    Split {
+        /// Create a branch for every commit instead of selecting the commits to split at
+        #[arg(long)]
+        by_commit: bool,
+    },
     /// Switch to base branch of the stack
     Base {},
     /// Switch to the above branch of the stack
@@ -53,8 +99,32 @@ pub enum Commands {
         #[clap(subcommand)]
         cmd: PrCommands,
     },
+    /// Work on several stack branches in parallel using git worktrees
+    Worktree {
+        #[clap(subcommand)]
+        cmd: WorktreeCommands,
+    },
+    /// Check the stacks for branches, parents and PR's that don't match the repository or github
+    Doctor {
+        /// Repair the problems that were found
+        #[arg(long)]
+        fix: bool,
+    },
     /// Delete all stacks and their branches
     Reset {},
+    /// Resume an interrupted sync, merge or remove
+    Continue {},
+    /// Roll back the local branches and state of an interrupted sync, merge or remove
+    Abort {},
+    /// Restore stack branches and state to a backup taken before a mutating command
+    Undo {
+        /// Id of the backup to restore, defaults to the most recent one
+        id: Option<String>,
+
+        /// List available backups instead of restoring one
+        #[arg(short, long)]
+        list: bool,
+    },
 }
 
 #[derive(Subcommand)]
@@ -65,5 +135,34 @@ pub enum PrCommands {
     /// List open PR's for all stack branches
     List {},
     /// Merge all stack pr's in sequence to the stacks base branch
-    Merge {},
+    Merge {
+        /// Merge method to use, defaults to `merge_method` from the config or `merge`
+        #[arg(short, long, value_enum)]
+        method: Option<MergeStrategy>,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum WorktreeCommands {
+    /// Create a worktree for a stack branch, or for every branch of the current stack that is
+    /// not checked out yet
+    Add {
+        /// Stack branch to create a worktree for
+        branch: Option<String>,
+
+        /// Directory to create the worktrees in, defaults to `<repo>.worktrees` next to the repo
+        #[arg(short, long)]
+        dir: Option<PathBuf>,
+    },
+    #[clap(alias = "ls")]
+    /// List the worktrees the branches of the current stack are checked out in
+    List {},
+}
+
+#[derive(ValueEnum, Serialize, Deserialize, Clone, Copy, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum MergeStrategy {
+    Merge,
+    Squash,
+    Rebase,
 }
@@ -3,19 +3,41 @@ use std::fs;
 use anyhow::{bail, Result};
 use serde::Deserialize;
 
-#[derive(Deserialize)]
+use crate::command::MergeStrategy;
+
+#[derive(Deserialize, Default)]
 pub struct GlobalConfig {
     pub personal_access_token: Option<String>,
+    /// Merge method used by `gs pr merge` when none is passed on the command line
+    pub merge_method: Option<MergeStrategy>,
+    /// Seconds `gs pr merge` waits for a PR to become mergeable, defaults to 10 minutes
+    pub merge_timeout_secs: Option<u64>,
+    /// Share the stack metadata with teammates through `refs/gstack/shared/*` refs that
+    /// `gs sync` fetches and pushes
+    pub share_stacks: Option<bool>,
+    /// Delete the local and remote branches `gs sync` finds merged into the base branch
+    pub delete_merged_branches: Option<bool>,
 }
 
 impl GlobalConfig {
+    /// Reads the config, falling back to the default config if there is no config file.
+    /// Commands that need github check for the token with [`GlobalConfig::personal_access_token`].
     pub fn read() -> Result<GlobalConfig> {
         let os_home = std::env::var("HOME")?;
         if let Ok(file_content) = fs::read_to_string(format!("{os_home}/.gstack/config.toml")) {
             let config: GlobalConfig = toml::from_str(file_content.as_str())?;
             Ok(config)
         } else {
-            bail!("Could not find gstack config at default location $HOME/.gstack/config.toml")
+            Ok(GlobalConfig::default())
+        }
+    }
+
+    pub fn personal_access_token(&self) -> Result<String> {
+        match &self.personal_access_token {
+            Some(token) => Ok(token.clone()),
+            None => bail!(
+                "No github personal access token found, add personal_access_token to the gstack config at $HOME/.gstack/config.toml"
+            ),
         }
     }
 }
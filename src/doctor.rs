@@ -0,0 +1,533 @@
+use anyhow::{bail, Result};
+use console::style;
+use dialoguer::{theme::ColorfulTheme, Input};
+use octocrab::{
+    models::{pulls::PullRequest, IssueState},
+    Octocrab,
+};
+use rustygit::Repository;
+
+use crate::{
+    pr_status,
+    repo_extensions::{RemoteRepoInfo, RepoExtenstions},
+    state::{GsState, StackPullRequest},
+};
+
+/// An inconsistency between the state and the local branches, the remote or GitHub.
+/// Stacks are referenced by their index in the state.
+pub enum Problem {
+    EmptyStack {
+        stack: usize,
+    },
+    MissingBase {
+        stack: usize,
+        base: String,
+        on_remote: bool,
+    },
+    MissingBranch {
+        stack: usize,
+        branch: String,
+        on_remote: bool,
+    },
+    /// The branch is recorded in several stacks or several times in the same stack
+    DuplicateBranch {
+        stack: usize,
+        branch: String,
+        first_stack: usize,
+    },
+    UnknownParent {
+        stack: usize,
+        branch: String,
+        parent: String,
+    },
+    /// The branch and its parent have no common history
+    UnrelatedToParent {
+        stack: usize,
+        branch: String,
+        parent: String,
+    },
+    /// The recorded fork point is not part of the branch anymore, e.g. after a manual rebase
+    StaleForkPoint {
+        stack: usize,
+        branch: String,
+    },
+    ClosedPullRequest {
+        stack: usize,
+        branch: String,
+        number: u64,
+    },
+    /// The PR was merged but its branch is still part of the stack
+    MergedPullRequest {
+        stack: usize,
+        branch: String,
+        number: u64,
+        head: String,
+    },
+    /// A PR is recorded for a branch that is not part of the stack anymore
+    StalePullRequest {
+        stack: usize,
+        branch: String,
+        number: u64,
+    },
+    /// The stack branch has an open PR that is not recorded
+    UnrecordedPullRequest {
+        stack: usize,
+        branch: String,
+        pull_request: StackPullRequest,
+    },
+    WrongPullRequestHead {
+        stack: usize,
+        branch: String,
+        number: u64,
+        head: String,
+    },
+    WrongPullRequestBase {
+        stack: usize,
+        branch: String,
+        number: u64,
+        base: String,
+        parent: String,
+    },
+    UnfinishedOperation {
+        operation: String,
+    },
+}
+
+impl Problem {
+    pub fn describe(&self, state: &GsState) -> String {
+        let name = |stack: &usize| style(stack_name(state, *stack)).cyan();
+        match self {
+            Problem::EmptyStack { stack } => format!("Stack {} has no branches", name(stack)),
+            Problem::MissingBase {
+                stack,
+                base,
+                on_remote,
+            } => format!(
+                "Base branch {} of stack {} doesn't exist locally{}",
+                style(base).green(),
+                name(stack),
+                remote_hint(*on_remote)
+            ),
+            Problem::MissingBranch {
+                stack,
+                branch,
+                on_remote,
+            } => format!(
+                "Branch {} of stack {} doesn't exist locally{}",
+                style(branch).green(),
+                name(stack),
+                remote_hint(*on_remote)
+            ),
+            Problem::DuplicateBranch {
+                stack,
+                branch,
+                first_stack,
+            } if stack == first_stack => format!(
+                "Branch {} is listed more than once in stack {}",
+                style(branch).green(),
+                name(stack)
+            ),
+            Problem::DuplicateBranch {
+                stack,
+                branch,
+                first_stack,
+            } => format!(
+                "Branch {} of stack {} already belongs to stack {}",
+                style(branch).green(),
+                name(stack),
+                name(first_stack)
+            ),
+            Problem::UnknownParent {
+                stack,
+                branch,
+                parent,
+            } => format!(
+                "Branch {} of stack {} is stacked on {}, which is not part of the stack",
+                style(branch).green(),
+                name(stack),
+                style(parent).green()
+            ),
+            Problem::UnrelatedToParent {
+                stack,
+                branch,
+                parent,
+            } => format!(
+                "Branch {} of stack {} has no common history with its parent {}, rebase it manually or remove it with {}",
+                style(branch).green(),
+                name(stack),
+                style(parent).green(),
+                style("gs remove").green()
+            ),
+            Problem::StaleForkPoint { stack, branch } => format!(
+                "The recorded fork point of branch {} of stack {} is not part of the branch anymore",
+                style(branch).green(),
+                name(stack)
+            ),
+            Problem::ClosedPullRequest {
+                stack,
+                branch,
+                number,
+            } => format!(
+                "PR #{} of branch {} of stack {} was closed",
+                number,
+                style(branch).green(),
+                name(stack)
+            ),
+            Problem::MergedPullRequest {
+                stack,
+                branch,
+                number,
+                ..
+            } => format!(
+                "PR #{} of branch {} of stack {} was merged, but the branch is still part of the stack",
+                number,
+                style(branch).green(),
+                name(stack)
+            ),
+            Problem::StalePullRequest {
+                stack,
+                branch,
+                number,
+            } => format!(
+                "PR #{} is recorded for branch {}, which is not part of stack {}",
+                number,
+                style(branch).green(),
+                name(stack)
+            ),
+            Problem::UnrecordedPullRequest {
+                stack,
+                branch,
+                pull_request,
+            } => format!(
+                "PR #{} of branch {} of stack {} is not recorded",
+                pull_request.number,
+                style(branch).green(),
+                name(stack)
+            ),
+            Problem::WrongPullRequestHead {
+                stack,
+                branch,
+                number,
+                head,
+            } => format!(
+                "PR #{} recorded for branch {} of stack {} belongs to branch {}",
+                number,
+                style(branch).green(),
+                name(stack),
+                style(head).green()
+            ),
+            Problem::WrongPullRequestBase {
+                stack,
+                branch,
+                number,
+                base,
+                parent,
+            } => format!(
+                "PR #{} of branch {} of stack {} targets {} instead of {}",
+                number,
+                style(branch).green(),
+                name(stack),
+                style(base).green(),
+                style(parent).green()
+            ),
+            Problem::UnfinishedOperation { operation } => format!(
+                "A {} was interrupted, run {} to resume it or {} to roll it back",
+                operation,
+                style("gs continue").green(),
+                style("gs abort").green()
+            ),
+        }
+    }
+}
+
+fn stack_name(state: &GsState, stack: usize) -> String {
+    match state.stacks[stack].name() {
+        name if name.is_empty() => format!("#{}", stack),
+        name => name,
+    }
+}
+
+fn remote_hint(on_remote: bool) -> &'static str {
+    match on_remote {
+        true => " but exists on the remote",
+        false => " or on the remote",
+    }
+}
+
+/// Cross-checks every stack against the local and remote branches
+pub fn check_branches(repo: &Repository, state: &GsState) -> Vec<Problem> {
+    let mut problems = vec![];
+    let mut seen: Vec<(&String, usize)> = vec![];
+    for (idx, stack) in state.stacks.iter().enumerate() {
+        if stack.branches.is_empty() {
+            problems.push(Problem::EmptyStack { stack: idx });
+            continue;
+        }
+        let base_exists = repo.head_sha(&stack.base_branch).is_ok();
+        if !base_exists {
+            problems.push(Problem::MissingBase {
+                stack: idx,
+                base: stack.base_branch.clone(),
+                on_remote: repo.has_remote_branch("origin", &stack.base_branch),
+            });
+        }
+        for branch in &stack.branches {
+            if let Some((_, first_stack)) = seen.iter().find(|(seen, _)| *seen == branch) {
+                problems.push(Problem::DuplicateBranch {
+                    stack: idx,
+                    branch: branch.clone(),
+                    first_stack: *first_stack,
+                });
+                continue;
+            }
+            seen.push((branch, idx));
+
+            if repo.head_sha(branch).is_err() {
+                problems.push(Problem::MissingBranch {
+                    stack: idx,
+                    branch: branch.clone(),
+                    on_remote: repo.has_remote_branch("origin", branch),
+                });
+                continue;
+            }
+            let Some(parent) = stack.parent(branch) else {
+                continue;
+            };
+            if *parent != stack.base_branch && !stack.branches.contains(parent) {
+                problems.push(Problem::UnknownParent {
+                    stack: idx,
+                    branch: branch.clone(),
+                    parent: parent.clone(),
+                });
+                continue;
+            }
+            if repo.head_sha(parent).is_err() {
+                continue;
+            }
+            if repo.merge_base(parent, branch).is_err() {
+                problems.push(Problem::UnrelatedToParent {
+                    stack: idx,
+                    branch: branch.clone(),
+                    parent: parent.clone(),
+                });
+            } else if stack
+                .fork_points
+                .get(branch)
+                .is_some_and(|fork_point| !repo.is_ancestor(fork_point, branch))
+            {
+                problems.push(Problem::StaleForkPoint {
+                    stack: idx,
+                    branch: branch.clone(),
+                });
+            }
+        }
+        for (branch, recorded) in &stack.pull_requests {
+            if !stack.branches.contains(branch) {
+                problems.push(Problem::StalePullRequest {
+                    stack: idx,
+                    branch: branch.clone(),
+                    number: recorded.number,
+                });
+            }
+        }
+    }
+    problems
+}
+
+/// Cross-checks the PR's recorded for the stack branches against GitHub
+pub async fn check_pull_requests(
+    github: &Octocrab,
+    remote: &RemoteRepoInfo,
+    open_pulls: &[PullRequest],
+    state: &GsState,
+) -> Result<Vec<Problem>> {
+    let pulls = github.pulls(&remote.owner, &remote.name);
+    let mut problems = vec![];
+    for (idx, stack) in state.stacks.iter().enumerate() {
+        for branch in &stack.branches {
+            if stack.pull_requests.contains_key(branch) {
+                continue;
+            }
+            if let Some(pr) = pr_status::pull_request_of(open_pulls, branch) {
+                problems.push(Problem::UnrecordedPullRequest {
+                    stack: idx,
+                    branch: branch.clone(),
+                    pull_request: StackPullRequest {
+                        number: pr.number,
+                        url: pr
+                            .html_url
+                            .as_ref()
+                            .map(|url| url.to_string())
+                            .unwrap_or_default(),
+                        head_sha: pr.head.sha.clone(),
+                    },
+                });
+            }
+        }
+        for (branch, recorded) in &stack.pull_requests {
+            // Reported by check_branches
+            if !stack.branches.contains(branch) {
+                continue;
+            }
+            let pr = pulls.get(recorded.number).await?;
+            if pr.head.ref_field != *branch {
+                problems.push(Problem::WrongPullRequestHead {
+                    stack: idx,
+                    branch: branch.clone(),
+                    number: pr.number,
+                    head: pr.head.ref_field.clone(),
+                });
+            } else if pr.merged_at.is_some() {
+                problems.push(Problem::MergedPullRequest {
+                    stack: idx,
+                    branch: branch.clone(),
+                    number: pr.number,
+                    head: pr.head.sha.clone(),
+                });
+            } else if pr.state != Some(IssueState::Open) {
+                problems.push(Problem::ClosedPullRequest {
+                    stack: idx,
+                    branch: branch.clone(),
+                    number: pr.number,
+                });
+            } else if let Some(parent) = stack.parent(branch) {
+                if pr.base.ref_field != *parent {
+                    problems.push(Problem::WrongPullRequestBase {
+                        stack: idx,
+                        branch: branch.clone(),
+                        number: pr.number,
+                        base: pr.base.ref_field.clone(),
+                        parent: parent.clone(),
+                    });
+                }
+            }
+        }
+    }
+    Ok(problems)
+}
+
+/// Repairs a problem, returns false if it can't be fixed automatically.
+/// Stacks that end up without branches are left for the caller to remove.
+pub async fn fix(
+    problem: &Problem,
+    repo: &Repository,
+    state: &mut GsState,
+    github: Option<(&Octocrab, &RemoteRepoInfo)>,
+) -> Result<bool> {
+    match problem {
+        Problem::EmptyStack { .. } => {}
+        Problem::MissingBase {
+            base,
+            on_remote: true,
+            ..
+        } => repo.fast_forward_from_remote("origin", base)?,
+        Problem::MissingBase {
+            stack,
+            base,
+            on_remote: false,
+        } => {
+            let new_base: String = Input::with_theme(&ColorfulTheme::default())
+                .with_prompt(format!(
+                    "New base branch for stack {}",
+                    stack_name(state, *stack)
+                ))
+                .interact_text()?;
+            if repo.head_sha(&new_base).is_err() {
+                bail!("Branch {} doesn't exist", new_base);
+            }
+            let stack = &mut state.stacks[*stack];
+            for parent in stack.parents.values_mut() {
+                if parent == base {
+                    *parent = new_base.clone();
+                }
+            }
+            stack.base_branch = new_base;
+        }
+        Problem::MissingBranch {
+            branch,
+            on_remote: true,
+            ..
+        } => repo.fast_forward_from_remote("origin", branch)?,
+        Problem::MissingBranch {
+            stack,
+            branch,
+            on_remote: false,
+        } => state.stacks[*stack].remove_branch(branch),
+        Problem::DuplicateBranch {
+            stack,
+            branch,
+            first_stack,
+        } => {
+            let branches = &mut state.stacks[*stack].branches;
+            if stack == first_stack {
+                // Keep the first occurrence
+                if let Some(last) = branches.iter().rposition(|b| b == branch) {
+                    branches.remove(last);
+                }
+            } else {
+                state.stacks[*stack].remove_branch(branch);
+            }
+        }
+        Problem::UnknownParent { stack, branch, .. } => {
+            let stack = &mut state.stacks[*stack];
+            stack
+                .parents
+                .insert(branch.clone(), stack.base_branch.clone());
+        }
+        Problem::UnrelatedToParent { .. } => return Ok(false),
+        Problem::StaleForkPoint { stack, branch } => {
+            state.stacks[*stack].fork_points.remove(branch);
+        }
+        Problem::ClosedPullRequest { stack, branch, .. }
+        | Problem::WrongPullRequestHead { stack, branch, .. }
+        | Problem::StalePullRequest { stack, branch, .. } => {
+            state.stacks[*stack].pull_requests.remove(branch);
+        }
+        Problem::MergedPullRequest {
+            stack,
+            branch,
+            head,
+            ..
+        } => {
+            // Like gs sync, the branches above only keep the commits made on top of the merged
+            // branch. They are rebased and their PR's retargeted by the next sync.
+            let stack = &mut state.stacks[*stack];
+            for child in stack.children(branch) {
+                stack.fork_points.insert(child, head.clone());
+            }
+            stack.remove_branch(branch);
+        }
+        Problem::UnrecordedPullRequest {
+            stack,
+            branch,
+            pull_request,
+        } => {
+            state.stacks[*stack]
+                .pull_requests
+                .insert(branch.clone(), pull_request.clone());
+        }
+        Problem::WrongPullRequestBase {
+            stack,
+            branch,
+            number,
+            ..
+        } => {
+            let Some((github, remote)) = github else {
+                return Ok(false);
+            };
+            // Earlier fixes might have changed the parent, e.g. by removing a merged branch.
+            // A parent that is a stack branch was pushed by the caller.
+            let Some(parent) = state.stacks[*stack].parent(branch) else {
+                return Ok(false);
+            };
+            github
+                .pulls(&remote.owner, &remote.name)
+                .update(*number)
+                .base(parent)
+                .send()
+                .await?;
+        }
+        Problem::UnfinishedOperation { .. } => return Ok(false),
+    }
+    Ok(true)
+}
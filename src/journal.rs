@@ -0,0 +1,108 @@
+use std::{fmt, fs, path::Path};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::{command::MergeStrategy, state::write_file};
+
+const JOURNAL_FILE: &str = "journal.ron";
+
+/// A mutating command that can be resumed with `gs continue` or rolled back with `gs abort`
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum Operation {
+    Sync {
+        update_descriptions: bool,
+    },
+    Restack {
+        /// Branch to start restacking from, the whole stack is restacked if not set
+        from: Option<String>,
+    },
+    Reorder {
+        /// New order of the stack branches, bottom first
+        branches: Vec<String>,
+    },
+    Fold {
+        branch: String,
+        /// Branch below the folded branch that takes over its commits
+        into: String,
+        folded_pr: Option<u64>,
+        surviving_pr: Option<u64>,
+    },
+    Merge {
+        strategy: MergeStrategy,
+    },
+    Remove {
+        branch: String,
+        delete_branch: bool,
+    },
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operation::Sync { .. } => write!(f, "sync"),
+            Operation::Restack { .. } => write!(f, "restack"),
+            Operation::Reorder { .. } => write!(f, "reorder"),
+            Operation::Fold { branch, .. } => write!(f, "fold of {}", branch),
+            Operation::Merge { .. } => write!(f, "pr merge"),
+            Operation::Remove { branch, .. } => write!(f, "remove of {}", branch),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum Step {
+    Pulled,
+    Rebased(String),
+    Pushed(String),
+    RemovedFromStack(String),
+    DeletedBranch(String),
+    /// gs sync found the branch merged and removed it from the stack
+    DetectedMerged(String),
+    DeletedRemoteBranch(String),
+    Merged {
+        number: u64,
+        branch: String,
+        head: String,
+    },
+    MergeFinished(u64),
+    Folded(String),
+    ClosedPullRequest(u64),
+}
+
+/// Progress of the operation that is currently running, stored next to the state file
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Journal {
+    pub operation: Operation,
+    /// Branch that was checked out when the operation started
+    pub original_branch: String,
+    /// Backup taken before the operation started, restored by `gs abort`
+    pub backup: String,
+    pub completed: Vec<Step>,
+}
+
+impl Journal {
+    pub fn read(gstack_dir: &Path) -> Result<Option<Journal>> {
+        match fs::read_to_string(gstack_dir.join(JOURNAL_FILE)) {
+            Ok(contents) => Ok(Some(ron::from_str(&contents)?)),
+            Err(_) => Ok(None),
+        }
+    }
+
+    pub fn write(&self, gstack_dir: &Path) -> Result<()> {
+        let string_value = ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())?;
+        write_file(&gstack_dir.join(JOURNAL_FILE), &string_value)
+    }
+
+    pub fn delete(gstack_dir: &Path) -> Result<()> {
+        let path = gstack_dir.join(JOURNAL_FILE);
+        if path.exists() {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    pub fn is_completed(&self, step: &Step) -> bool {
+        self.completed.contains(step)
+    }
+}
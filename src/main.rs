@@ -1,71 +1,114 @@
-use std::{path::PathBuf, str::FromStr, sync::Arc, thread::current, time::Duration};
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
 
 use clap::Parser;
 use console::{pad_str, style};
-use dialoguer::{theme::ColorfulTheme, Confirm, Input, Select};
+use dialoguer::{theme::ColorfulTheme, Confirm, Input, MultiSelect, Select};
 use octocrab::{
-    models::pulls::PullRequest,
+    models::{pulls::PullRequest, IssueState},
     params::pulls::{MergeMethod, Sort},
     pulls::PullRequestHandler,
     Octocrab,
 };
-use rustygit::types::BranchName;
-use tokio::time::sleep;
+use rustygit::{types::BranchName, Repository};
 
 use crate::{
-    command::{Cli, Commands},
+    command::{Cli, Commands, MergeStrategy, WorktreeCommands},
     config::GlobalConfig,
-    repo_extensions::RepoExtenstions,
-    state::{GitStack, GsState},
+    journal::{Journal, Operation, Step},
+    repo_extensions::{RebaseConflict, RepoExtenstions},
+    state::{GitStack, GsState, StackPullRequest, StateLock},
 };
-use anyhow::Result;
+use anyhow::{anyhow, bail, Result};
 
+mod backup;
 mod command;
 mod config;
+mod doctor;
+mod journal;
+mod migrations;
+mod pr_status;
 mod repo_extensions;
+mod shared_stacks;
 mod state;
 
 struct GsContext {
     repo: rustygit::Repository,
-    base_path: PathBuf,
-    github: Arc<Octocrab>,
+    /// Directory of the state and journal inside the git common dir
+    gstack_dir: PathBuf,
+    github: Option<Arc<Octocrab>>,
+    config: GlobalConfig,
     state: GsState,
+    journal: Option<Journal>,
 }
 const VERSION: &str = env!("CARGO_PKG_VERSION");
+const REORDER_FILE: &str = "REORDER_EDITMSG";
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let cli = Cli::parse();
-    let base_path = PathBuf::from_str(".")?;
-    let repo = rustygit::Repository::new(base_path.clone());
+    let path = cli.path.clone().unwrap_or(PathBuf::from("."));
+    let (repo, git_common_dir) = repo_extensions::open_repository(&path)?;
+    let gstack_dir = git_common_dir.join("gstack");
+    // Held until the command finished
+    let _lock = StateLock::acquire(&gstack_dir)?;
     let config = GlobalConfig::read()?;
-    let github = Octocrab::builder()
-        .personal_token(config.personal_access_token.unwrap())
-        .build()?;
-    let state = GsState::init(base_path.clone())?;
+    let github = match config.personal_access_token() {
+        Ok(token) => Some(Arc::new(Octocrab::builder().personal_token(token).build()?)),
+        Err(_) => None,
+    };
+    let state = GsState::init(gstack_dir.clone())?;
+    let journal = Journal::read(&gstack_dir)?;
     let mut ctx = GsContext {
         repo,
-        base_path,
-        github: Arc::new(github),
+        gstack_dir,
+        github,
+        config,
         state,
+        journal,
     };
 
     match &cli.command {
         Some(Commands::New { prefix, name }) => ctx.new_stack(prefix, name)?,
         Some(Commands::Add { name }) => ctx.add_to_stack(name)?,
+        Some(Commands::Insert {
+            name,
+            after,
+            before,
+        }) => ctx.insert_into_stack(name, after, before).await?,
         Some(Commands::Remove {}) => ctx.remove_current_branch().await?,
         Some(Commands::List {}) => ctx.list()?,
         Some(Commands::Change {}) => ctx.change()?,
-        Some(Commands::Sync {}) => ctx.sync(true).await?,
+        Some(Commands::Sync {}) => ctx.start_sync(true).await?,
         Some(Commands::Up {}) => ctx.checkout_above()?,
         Some(Commands::Down {}) => ctx.checkout_below()?,
         Some(Commands::Base {}) => ctx.checkout_base()?,
+        Some(Commands::Restack { upstack }) => ctx.start_restack(*upstack).await?,
+        Some(Commands::Reorder {}) => ctx.start_reorder().await?,
+        Some(Commands::Fold {}) => ctx.start_fold().await?,
+        Some(Commands::Split { by_commit }) => ctx.split(*by_commit)?,
+        Some(Commands::Adopt { branches, from_prs }) => ctx.adopt(branches, *from_prs).await?,
+        Some(Commands::CheckoutPr { number }) => ctx.checkout_pr(*number).await?,
         Some(Commands::Pr { cmd }) => match cmd {
             command::PrCommands::New {} => ctx.create_pull_requests().await?,
             command::PrCommands::List {} => ctx.list_pull_requests().await?,
-            command::PrCommands::Merge {} => ctx.merge_pull_requests().await?,
+            command::PrCommands::Merge { method } => ctx.start_merge(method).await?,
         },
+        Some(Commands::Worktree { cmd }) => match cmd {
+            WorktreeCommands::Add { branch, dir } => ctx.add_worktrees(branch, dir)?,
+            WorktreeCommands::List {} => ctx.list_worktrees()?,
+        },
+        Some(Commands::Doctor { fix }) => ctx.doctor(*fix).await?,
         Some(Commands::Reset {}) => ctx.reset()?,
+        Some(Commands::Continue {}) => ctx.continue_operation().await?,
+        Some(Commands::Abort {}) => ctx.abort_operation()?,
+        Some(Commands::Undo { id, list }) => ctx.undo(id, *list)?,
         None => println!(
             "Welcome to {} version {}! Run {} to see available commands.",
             style("G-Stack").bold().cyan(),
@@ -78,6 +121,14 @@ async fn main() -> Result<()> {
 }
 
 impl GsContext {
+    /// The github client, only available if a personal access token is configured
+    fn github(&self) -> Result<Arc<Octocrab>> {
+        match &self.github {
+            Some(github) => Ok(github.clone()),
+            None => Err(self.config.personal_access_token().unwrap_err()),
+        }
+    }
+
     fn new_stack(&mut self, prefix: &Option<String>, name: &Option<String>) -> Result<()> {
         let prefix_val = match prefix {
             Some(value) => value.to_string(),
@@ -100,22 +151,101 @@ impl GsContext {
             }
         };
         let current_branch = self.repo.current_branch()?;
-        let branch_name = GsContext::get_branch_name(&prefix_val, &name_val)?;
+        let branch_name = GsContext::get_branch_name(&Some(prefix_val.clone()), &name_val)?;
         self.repo
             .create_branch_from_startpoint(&branch_name, current_branch.to_string().as_str())?;
         self.repo.switch_branch(&branch_name)?;
+        let fork_point = self.repo.head_sha(&current_branch.to_string())?;
         self.state.stacks.push(GitStack {
+            id: branch_name.to_string(),
             base_branch: current_branch.to_string(),
             prefix: Some(prefix_val.clone()),
             branches: vec![branch_name.to_string()],
+            parents: BTreeMap::from([(branch_name.to_string(), current_branch.to_string())]),
+            fork_points: BTreeMap::from([(branch_name.to_string(), fork_point)]),
+            ..Default::default()
         });
-        self.state.write(self.base_path.clone())?;
+        self.state.write(self.gstack_dir.clone())?;
 
         println!("Created new stack with base branch: {}", branch_name);
         Ok(())
     }
 
+    /// Stacks a new branch on top of the current branch, next to any existing children
     fn add_to_stack(&mut self, name: &Option<String>) -> Result<()> {
+        let Some(current_branch) = self.current_stack().map(|_| self.repo.current_branch()) else {
+            bail!("Not on a stack branch");
+        };
+        let name = self.create_stack_branch(name, &current_branch?.to_string(), &[])?;
+        println!("Stacked a new branch with name: {}", name);
+        Ok(())
+    }
+
+    /// Creates a new branch above `after` or below `before` (above the current branch if neither
+    /// is given), restacks the branches above it and points their PR's at the new branch
+    async fn insert_into_stack(
+        &mut self,
+        name: &Option<String>,
+        after: &Option<String>,
+        before: &Option<String>,
+    ) -> Result<()> {
+        let Some(stack) = self.current_stack() else {
+            bail!("Not on a stack branch");
+        };
+        let in_stack = |branch: &String| match stack.branches.contains(branch) {
+            true => Ok(branch.clone()),
+            false => Err(anyhow!("{} is not a branch of the current stack", branch)),
+        };
+        let (parent, children) = match (after, before) {
+            (None, Some(before)) => {
+                let before = in_stack(before)?;
+                (stack.parent(&before).unwrap().clone(), vec![before])
+            }
+            (after, _) => {
+                let after = match after {
+                    Some(after) => in_stack(after)?,
+                    None => self.repo.current_branch()?.to_string(),
+                };
+                (after.clone(), stack.children(&after))
+            }
+        };
+
+        let name = self.create_stack_branch(name, &parent, &children)?;
+        if children.is_empty() {
+            println!("Stacked a new branch with name: {}", name);
+            return Ok(());
+        }
+        println!(
+            "Inserted branch {} between {} and {}",
+            style(&name).green(),
+            style(&parent).cyan(),
+            style(children.join(", ")).cyan()
+        );
+        self.run_operation(Operation::Restack {
+            from: Some(name.to_string()),
+        })
+        .await?;
+        if self.github.is_some() {
+            let open_pulls = self.get_pull_requests().await?;
+            self.update_pr_bases(&open_pulls).await?;
+        }
+        Ok(())
+    }
+
+    /// Creates and checks out a new branch on top of `parent` and stacks `children` on it
+    fn create_stack_branch(
+        &mut self,
+        name: &Option<String>,
+        parent: &str,
+        children: &[String],
+    ) -> Result<BranchName> {
+        let stack = self.current_stack().unwrap();
+        let prefix = stack.prefix.clone();
+        let position = stack
+            .branches
+            .iter()
+            .position(|branch| branch == parent)
+            .map_or(0, |idx| idx + 1);
         let name_val = match name {
             Some(value) => value.to_string(),
             None => {
@@ -126,26 +256,294 @@ impl GsContext {
                 input
             }
         };
-        let prefix = self.current_stack().unwrap().prefix.clone().unwrap();
         let name = GsContext::get_branch_name(&prefix, &name_val)?;
-        self.current_stack_mut()
-            .unwrap()
-            .branches
-            .push(name.to_string());
-        let current_branch = self.repo.current_branch()?;
-        self.repo
-            .create_branch_from_startpoint(&name, current_branch.to_string().as_str())?;
-        self.repo.switch_branch(&name)?;
-        self.state.write(self.base_path.clone())?;
+        let fork_point = self.repo.head_sha(parent)?;
+        let stack = self.current_stack_mut().unwrap();
+        stack.branches.insert(position, name.to_string());
+        stack.parents.insert(name.to_string(), parent.to_string());
+        for child in children {
+            stack.parents.insert(child.clone(), name.to_string());
+        }
+        stack.fork_points.insert(name.to_string(), fork_point);
+        self.repo.create_branch_from_startpoint(&name, parent)?;
+        self.state.write(self.gstack_dir.clone())?;
+        Ok(name)
+    }
 
-        println!("Stacked a new branch with name: {}", name);
+    /// Adds existing branches to the current stack, or to a new stack on top of the current
+    /// branch. Parents are inferred from git ancestry, or from the base branches of open PR's.
+    async fn adopt(&mut self, branches: &[String], from_prs: bool) -> Result<()> {
+        let current_branch = self.repo.current_branch()?.to_string();
+        let (base, mut parents, mut prs) = match from_prs {
+            true => {
+                self.adopted_parents_from_prs(branches, &current_branch)
+                    .await?
+            }
+            false => (
+                current_branch.clone(),
+                self.adopted_parents_from_ancestry(branches, &current_branch)?,
+                vec![],
+            ),
+        };
+        let tracked = |branch: &String| {
+            self.state
+                .stacks
+                .iter()
+                .any(|stack| stack.branches.contains(branch))
+        };
+        if from_prs {
+            // Only PR's that were stacked on top since the stack was adopted are added
+            parents.retain(|branch, _| !tracked(branch));
+            prs.retain(|(branch, _)| parents.contains_key(branch));
+            if parents.is_empty() {
+                println!("All branches of the PR stack already belong to a stack");
+                return Ok(());
+            }
+        } else if let Some(adopted) = parents.keys().find(|branch| tracked(branch)) {
+            bail!("{} already belongs to a stack", adopted);
+        }
+
+        // Every branch has to come after its parent
+        let mut ordered: Vec<String> = vec![];
+        while ordered.len() < parents.len() {
+            let next = parents.iter().find(|(branch, parent)| {
+                !ordered.contains(branch)
+                    && (!parents.contains_key(*parent) || ordered.contains(parent))
+            });
+            match next {
+                Some((branch, _)) => ordered.push(branch.clone()),
+                None => bail!("The adopted branches form a cycle"),
+            }
+        }
+        let mut fork_points = BTreeMap::new();
+        for (branch, parent) in &parents {
+            fork_points.insert(branch.clone(), self.repo.merge_base(parent, branch)?);
+        }
+
+        // PR stacks extend the stack the parent of their bottom branch belongs to
+        let stack_idx = self.state.stacks.iter().position(|stack| match from_prs {
+            true => parents
+                .values()
+                .any(|parent| stack.branches.contains(parent)),
+            false => stack.branches.contains(&current_branch),
+        });
+        match stack_idx {
+            Some(idx) => {
+                let stack = &mut self.state.stacks[idx];
+                stack.branches.extend(ordered.iter().cloned());
+                stack.parents.extend(parents);
+                stack.fork_points.extend(fork_points);
+            }
+            None => self.state.stacks.push(GitStack {
+                id: ordered[0].clone(),
+                prefix: None,
+                base_branch: base,
+                branches: ordered.clone(),
+                parents,
+                fork_points,
+                ..Default::default()
+            }),
+        }
+        for (branch, pr) in &prs {
+            self.record_pull_request(branch, pr);
+        }
+        self.state.write(self.gstack_dir.clone())?;
+
+        for branch in &ordered {
+            println!("Adopted branch {}", style(branch).green());
+        }
         Ok(())
     }
 
-    fn get_branch_name(prefix: &String, name: &String) -> Result<BranchName> {
-        Ok(BranchName::from_str(
-            format!("{}/{}", prefix.as_str(), name.as_str()).as_str(),
-        )?)
+    /// The closest adopted or stack branch that is an ancestor of each branch is its parent,
+    /// branches without one are stacked on the base branch
+    fn adopted_parents_from_ancestry(
+        &self,
+        branches: &[String],
+        current_branch: &String,
+    ) -> Result<BTreeMap<String, String>> {
+        if branches.is_empty() {
+            bail!("Pass the branches to adopt or use --from-prs");
+        }
+        let stack = self.current_stack();
+        let base = match stack {
+            Some(stack) => stack.base_branch.clone(),
+            None if !branches.contains(current_branch) => current_branch.clone(),
+            None => bail!("Check out the branch the adopted branches are based on first"),
+        };
+        let mut candidates: Vec<String> = stack.map(|s| s.branches.clone()).unwrap_or_default();
+        candidates.extend(branches.iter().cloned());
+        for branch in branches {
+            self.repo.head_sha(branch)?;
+        }
+
+        let mut parents = BTreeMap::new();
+        for branch in branches {
+            let ancestors: Vec<&String> = candidates
+                .iter()
+                .filter(|c| *c != branch && self.repo.is_ancestor(c, branch))
+                .collect();
+            let closest = ancestors.iter().find(|c| {
+                !ancestors
+                    .iter()
+                    .any(|d| d != *c && self.repo.is_ancestor(c, d) && !self.repo.is_ancestor(d, c))
+            });
+            let parent = closest.map(|c| (*c).clone()).unwrap_or(base.clone());
+            parents.insert(branch.clone(), parent);
+        }
+        Ok(parents)
+    }
+
+    /// Follows the base branches of the open PR's of the given branches down to a branch
+    /// without PR, which becomes the base, and adds all PR's stacked on top of them
+    async fn adopted_parents_from_prs(
+        &self,
+        branches: &[String],
+        current_branch: &str,
+    ) -> Result<(String, BTreeMap<String, String>, Vec<(String, PullRequest)>)> {
+        let open_pulls = self.get_open_pull_requests().await?;
+        let pr_of = |branch: &String| pr_status::pull_request_of(&open_pulls, branch);
+        let start = match branches.is_empty() {
+            true => vec![current_branch.to_string()],
+            false => branches.to_vec(),
+        };
+
+        let mut parents = BTreeMap::new();
+        let mut prs = vec![];
+        let mut forked = vec![];
+        for branch in start {
+            // Only the adopted branches themselves can belong to a PR from a fork, nothing can
+            // be stacked on a branch that only exists in a fork
+            let fork_pr = match pr_of(&branch) {
+                Some(_) => None,
+                None => pr_status::fork_pull_request_of(&open_pulls, &branch),
+            };
+            if let Some(pr) = fork_pr {
+                parents.insert(branch.clone(), pr.base.ref_field.clone());
+                prs.push((branch.clone(), pr.clone()));
+                forked.push(branch);
+                continue;
+            }
+            let mut branch = branch;
+            while let Some(pr) = pr_of(&branch) {
+                if parents.contains_key(&branch) {
+                    break;
+                }
+                parents.insert(branch.clone(), pr.base.ref_field.clone());
+                prs.push((branch.clone(), pr.clone()));
+                branch = pr.base.ref_field.clone();
+            }
+        }
+        loop {
+            let stacked: Vec<&PullRequest> = open_pulls
+                .iter()
+                .filter(|pr| {
+                    !pr_status::is_from_fork(pr)
+                        && !parents.contains_key(&pr.head.ref_field)
+                        && parents.contains_key(&pr.base.ref_field)
+                        && !forked.contains(&pr.base.ref_field)
+                })
+                .collect();
+            if stacked.is_empty() {
+                break;
+            }
+            for pr in stacked {
+                parents.insert(pr.head.ref_field.clone(), pr.base.ref_field.clone());
+                prs.push((pr.head.ref_field.clone(), pr.clone()));
+            }
+        }
+        if parents.is_empty() {
+            bail!("No open PR's found for the adopted branches");
+        }
+
+        let mut bases: Vec<&String> = parents
+            .values()
+            .filter(|parent| !parents.contains_key(*parent))
+            .collect();
+        bases.sort();
+        bases.dedup();
+        if bases.is_empty() {
+            bail!("The PR's are stacked on each other without a base branch");
+        }
+        if bases.len() > 1 {
+            bail!(
+                "The PR's target different base branches: {}",
+                bases
+                    .iter()
+                    .map(|b| b.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+        let base = bases[0].clone();
+
+        // PR refs also work for branches of PR's opened from forks
+        for (branch, pr) in &prs {
+            let Ok(local) = self.repo.head_sha(branch) else {
+                let refspec = format!("pull/{}/head:{}", pr.number, branch);
+                self.repo.cmd(["fetch", "origin", refspec.as_str()])?;
+                continue;
+            };
+            let head = &pr.head.sha;
+            if local == *head || self.repo.is_ancestor(head, &local) {
+                continue;
+            }
+            let refspec = format!("pull/{}/head", pr.number);
+            self.repo.cmd(["fetch", "origin", refspec.as_str()])?;
+            if self.repo.is_ancestor(&local, head) {
+                self.fast_forward_branch(branch, head)?;
+                println!(
+                    "Updated branch {} from PR #{}",
+                    style(branch).green(),
+                    pr.number
+                );
+            } else {
+                println!(
+                    "Branch {} has local commits that are not part of PR #{}, keeping the local branch",
+                    style(branch).yellow(),
+                    pr.number
+                );
+            }
+        }
+        if self.repo.head_sha(&base).is_err() {
+            self.repo.fast_forward_from_remote("origin", &base)?;
+        }
+        Ok((base, parents, prs))
+    }
+
+    /// Fetches the stack a PR belongs to and records it, so it can be navigated like a local stack
+    async fn checkout_pr(&mut self, number: u64) -> Result<()> {
+        let remote = self.repo.remote_repo_info()?;
+        let github = self.github()?;
+        let pr = github.pulls(remote.owner, remote.name).get(number).await?;
+        let branch = pr.head.ref_field.clone();
+        if branch == pr.base.ref_field {
+            bail!(
+                "PR #{} was opened from a fork with a branch named like its base branch {}, fetch it under another name with {}",
+                number,
+                branch,
+                style(format!("git fetch origin pull/{}/head:<branch>", number)).green()
+            );
+        }
+        self.adopt(std::slice::from_ref(&branch), true).await?;
+        self.checkout(&branch)?;
+        let stack = self
+            .state
+            .stacks
+            .iter()
+            .find(|stack| stack.branches.contains(&branch))
+            .unwrap();
+        Self::list_stack_branches(stack)
+    }
+
+    /// Branch names of stacks without a prefix (e.g. adopted ones) are used as they are
+    fn get_branch_name(prefix: &Option<String>, name: &str) -> Result<BranchName> {
+        match prefix {
+            Some(prefix) => Ok(BranchName::from_str(
+                format!("{}/{}", prefix, name).as_str(),
+            )?),
+            None => Ok(BranchName::from_str(name)?),
+        }
     }
 
     fn current_stack(&self) -> Option<&GitStack> {
@@ -178,7 +576,7 @@ impl GsContext {
                     .iter()
                     .position(|branch| branch == &current_branch.to_string());
                 if let Some(idx) = branch_idx {
-                    return Some((&stack, idx));
+                    return Some((stack, idx));
                 }
             }
         };
@@ -195,6 +593,11 @@ impl GsContext {
     }
 
     fn list_stack_branches(stack: &GitStack) -> Result<()> {
+        if !stack.is_linear() {
+            println!("{}", style(&stack.base_branch).cyan());
+            Self::list_branch_tree(stack, &stack.base_branch, "");
+            return Ok(());
+        }
         let width = 20;
         for (i, branch) in stack.branches.iter().enumerate().rev() {
             let str = format!("({}): {}", i, style(branch).cyan());
@@ -216,9 +619,32 @@ impl GsContext {
         Ok(())
     }
 
-    fn list_stacks(stacks: &Vec<GitStack>) -> Result<()> {
+    /// Prints the children of `parent` and their children as a tree
+    fn list_branch_tree(stack: &GitStack, parent: &str, indent: &str) {
+        let children = stack.children(parent);
+        for (i, child) in children.iter().enumerate() {
+            let last = i == children.len() - 1;
+            let idx = stack.branches.iter().position(|b| b == child).unwrap();
+            println!(
+                "{}{} ({}): {}",
+                indent,
+                style(if last {
+                    "\u{2514}\u{2500}"
+                } else {
+                    "\u{251c}\u{2500}"
+                })
+                .magenta(),
+                idx,
+                style(child).cyan()
+            );
+            let indent = format!("{}{}", indent, if last { "   " } else { "\u{2502}  " });
+            Self::list_branch_tree(stack, child, &indent);
+        }
+    }
+
+    fn list_stacks(stacks: &[GitStack]) -> Result<()> {
         for (i, stack) in stacks.iter().enumerate() {
-            println!("({}): {}", i, style(stack.prefix.clone().unwrap()).cyan());
+            println!("({}): {}", i, style(stack.name()).cyan());
         }
         Ok(())
     }
@@ -240,14 +666,14 @@ impl GsContext {
                 .interact()
                 .unwrap();
             let branch = &stack.branches.get(options.len() - branch_idx - 1).unwrap();
-            self.repo.switch_branch(&BranchName::from_str(branch)?)?;
+            self.checkout(branch)?;
         } else {
             let stacks: Vec<String> = self
                 .state
                 .stacks
                 .iter()
                 .enumerate()
-                .map(|(i, stack)| format!("({}): {}", i, stack.prefix.clone().unwrap()))
+                .map(|(i, stack)| format!("({}): {}", i, stack.name()))
                 .collect();
 
             let stack_idx = Select::with_theme(&ColorfulTheme::default())
@@ -261,263 +687,1267 @@ impl GsContext {
             let selected_stack = self.state.stacks.get(stack_idx).unwrap();
             let branch = selected_stack.branches.first().unwrap();
 
-            self.repo.switch_branch(&BranchName::from_str(branch)?)?;
+            self.checkout(branch)?;
         }
         Ok(())
     }
 
     fn checkout_base(&self) -> Result<()> {
         if let Some(stack) = self.current_stack() {
-            self.repo
-                .switch_branch(&BranchName::from_str(stack.base_branch.as_str())?)?;
+            self.checkout(&stack.base_branch)?;
         }
         Ok(())
     }
 
     fn checkout_above(&self) -> Result<()> {
         if let Some((stack, idx)) = self.current_stack_position() {
-            if let Some(branch) = stack.branches.get(idx + 1) {
-                self.repo.switch_branch(&BranchName::from_str(branch)?)?;
-            }
+            let children = stack.children(&stack.branches[idx]);
+            let branch = match children.len() {
+                0 => return Ok(()),
+                1 => &children[0],
+                _ => {
+                    let child_idx = Select::with_theme(&ColorfulTheme::default())
+                        .with_prompt("Select Branch")
+                        .default(0)
+                        .items(&children)
+                        .interact()
+                        .unwrap();
+                    &children[child_idx]
+                }
+            };
+            self.checkout(branch)?;
         }
         Ok(())
     }
 
     fn checkout_below(&self) -> Result<()> {
         if let Some((stack, idx)) = self.current_stack_position() {
-            if let Some(branch) = stack.branches.get(idx - 1) {
-                self.repo.switch_branch(&BranchName::from_str(branch)?)?;
+            let parent = stack.parent(&stack.branches[idx]);
+            if let Some(branch) = parent.filter(|parent| **parent != stack.base_branch) {
+                self.checkout(branch)?;
             }
         }
         Ok(())
     }
 
-    async fn sync(&self, update_descriptions: bool) -> Result<()> {
-        let current_branch = self.repo.current_branch()?;
-        let branches = &self.current_stack().unwrap().branches;
-        self.repo.pull_all(branches).ok();
-        for (i, branch) in branches.clone().iter().enumerate() {
-            let rebase_on = match i {
-                0 => &self.current_stack().unwrap().base_branch,
-                _ => &branches[i - 1],
-            };
-            self.repo.rebase(
-                BranchName::from_str(branch)?,
-                BranchName::from_str(rebase_on)?,
-            )?;
-            self.repo
-                .force_push_to_upstream("origin", &BranchName::from_str(branch)?)?;
-        }
-        let open_pulls = self.get_pull_requests().await?;
-        let remote = self.repo.remote_repo_info()?;
-        let pulls = self.github.pulls(remote.owner, remote.name);
-        if update_descriptions {
-            self.update_pr_descriptions(&pulls, open_pulls).await?;
+    /// Switches to a branch, or prints the path of the worktree it is checked out in so the
+    /// shell can change into it, e.g. with `cd "$(gs change)"`
+    fn checkout(&self, branch: &str) -> Result<()> {
+        match self.repo.other_worktree(branch)? {
+            Some(path) => {
+                eprintln!(
+                    "{} is checked out in another worktree",
+                    style(branch).green()
+                );
+                println!("{}", path.display());
+            }
+            None => self.repo.switch_branch(&BranchName::from_str(branch)?)?,
         }
-        self.repo.switch_branch(&current_branch)?;
-
         Ok(())
     }
 
-    async fn create_pull_requests(&self) -> Result<()> {
-        self.sync(false).await?;
-        let stack = &self.current_stack().unwrap();
-        let branches = &stack.branches;
-        let remote = self.repo.remote_repo_info()?;
-        let pulls = self.github.pulls(remote.owner, remote.name);
-        let open_pulls = self.get_pull_requests().await?;
+    /// Fast forwards a local branch to `target`. Branches that are checked out in a worktree
+    /// are merged there to update the working tree, all others only have their ref moved.
+    fn fast_forward_branch(&self, branch: &str, target: &str) -> Result<()> {
+        if self.repo.current_branch()?.to_string() == branch {
+            self.repo.cmd(["merge", "--ff-only", target])?;
+        } else if let Some(repo) = self.worktree_repo(branch)? {
+            repo.cmd(["merge", "--ff-only", target])?;
+        } else {
+            let local = self.repo.head_sha(branch)?;
+            let branch_ref = format!("refs/heads/{}", branch);
+            self.repo
+                .cmd(["update-ref", branch_ref.as_str(), target, local.as_str()])?;
+        }
+        Ok(())
+    }
 
-        let draft = Confirm::new()
-            .with_prompt("Create as draft?")
-            .interact()
-            .unwrap();
+    /// Repository of the worktree `branch` is checked out in, if that is not the current one
+    fn worktree_repo(&self, branch: &str) -> Result<Option<Repository>> {
+        Ok(self.repo.other_worktree(branch)?.map(Repository::new))
+    }
 
-        let mut created_pulls = vec![];
-        for (i, branch) in branches.iter().enumerate() {
-            if let Some(pr) = self.get_branch_pr(&open_pulls, branch) {
-                created_pulls.push(pr);
-                continue;
+    /// Creates a worktree for the given stack branch, or for every branch of the current stack
+    /// that is not checked out in any worktree yet
+    fn add_worktrees(&self, branch: &Option<String>, dir: &Option<PathBuf>) -> Result<()> {
+        let Some(stack) = self.current_stack() else {
+            bail!("Not on a stack branch");
+        };
+        let worktrees = self.repo.worktrees()?;
+        let branches = match branch {
+            Some(branch) => {
+                if !stack.branches.contains(branch) {
+                    bail!("{} is not a branch of the current stack", branch);
+                }
+                if let Some(path) = worktrees.get(branch) {
+                    bail!("{} is already checked out in {}", branch, path.display());
+                }
+                vec![branch.clone()]
             }
-            let base = match i {
-                0 => &self.current_stack().unwrap().base_branch,
-                _ => &branches[i - 1],
-            };
-
-            let title = format!(
-                "{} (#{}) - {}",
-                stack.prefix.clone().unwrap(),
-                i,
-                branch.split('/').last().unwrap()
-            );
-
-            println!("base: {}, title: {}", base, title);
-            let pr = pulls
-                .create(title, branch, base)
-                .draft(draft)
-                .body("---")
-                .send()
-                .await?;
+            None => stack
+                .branches
+                .iter()
+                .filter(|branch| !worktrees.contains_key(*branch))
+                .cloned()
+                .collect(),
+        };
+        let dir = match dir {
+            Some(dir) => dir.clone(),
+            None => self.default_worktree_dir()?,
+        };
+        for branch in branches {
+            let path = dir.join(branch.replace('/', "-"));
+            self.repo.add_worktree(&branch, &path)?;
             println!(
-                "#{}: {}",
-                pr.number,
-                style(pr.html_url.clone().unwrap()).blue()
+                "Checked out {} in {}",
+                style(&branch).green(),
+                path.display()
             );
-            created_pulls.push(pr);
         }
-
-        self.update_pr_descriptions(&pulls, created_pulls).await?;
         Ok(())
     }
 
-    async fn update_pr_descriptions(
-        &self,
-        pulls: &PullRequestHandler<'_>,
-        prs: Vec<PullRequest>,
-    ) -> Result<()> {
-        for pr in prs.iter() {
-            let mut body = pr
-                .body
-                .clone()
-                .unwrap_or("".to_string())
-                .lines()
-                .take_while(|line| !line.contains("---"))
-                .collect::<Vec<&str>>()
-                .join("\n");
-
-            body.push_str("\n---\n");
-            prs.iter().rev().for_each(|p| {
-                body.push_str(format!("- #{}", p.number).as_str());
-                if pr.number == p.number {
-                    body.push_str(" (This PR)");
-                }
-                body.push('\n');
-            });
-            body = body.clone() + "\n**Created by [gstack](https://github.com/Bendzae/gstack)**";
+    /// `<repo>.worktrees` next to the main worktree of the repository
+    fn default_worktree_dir(&self) -> Result<PathBuf> {
+        let Some(main_worktree) = self.gstack_dir.parent().and_then(Path::parent) else {
+            bail!("Could not determine the main worktree of the repository");
+        };
+        let name = main_worktree
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+        Ok(main_worktree.with_file_name(format!("{}.worktrees", name)))
+    }
 
-            pulls.update(pr.number).body(body).send().await?;
-            println!("Updated description for PR: #{}", pr.number);
+    fn list_worktrees(&self) -> Result<()> {
+        let Some(stack) = self.current_stack() else {
+            bail!("Not on a stack branch");
+        };
+        let worktrees = self.repo.worktrees()?;
+        for branch in &stack.branches {
+            match worktrees.get(branch) {
+                Some(path) => println!("{} {}", style(branch).green(), path.display()),
+                None => println!("{} {}", style(branch).green(), style("-").dim()),
+            }
         }
         Ok(())
     }
 
-    async fn list_pull_requests(&self) -> Result<()> {
-        let open_pulls = self.get_pull_requests().await?;
-        for pr in open_pulls.iter() {
-            println!("#{}: {} ", pr.number, pr.html_url.clone().unwrap());
+    async fn start_sync(&mut self, update_descriptions: bool) -> Result<()> {
+        // A teammate's stack is imported by checking out one of its branches and syncing. Only
+        // the shared stacks are fetched here, the branches are fetched by the sync itself.
+        if self.current_stack().is_none() && self.config.share_stacks.unwrap_or(false) {
+            self.repo
+                .cmd(["fetch", "origin", shared_stacks::fetch_refspec().as_str()])?;
+            self.pull_shared_stacks()?;
         }
-        Ok(())
+        if self.current_stack().is_none() {
+            bail!("Not on a stack branch");
+        }
+        self.github()?;
+        self.run_operation(Operation::Sync {
+            update_descriptions,
+        })
+        .await
     }
 
-    async fn get_pull_requests(&self) -> Result<Vec<PullRequest>> {
+    async fn sync(&mut self, update_descriptions: bool) -> Result<()> {
+        let github = self.github()?;
+        let current_branch = self.repo.current_branch()?;
+        let share_stacks = self.config.share_stacks.unwrap_or(false);
+        if !self.is_step_completed(&Step::Pulled) {
+            let known_remote_heads = self.known_remote_heads();
+            self.fetch_remote(share_stacks)?;
+            if share_stacks {
+                self.pull_shared_stacks()?;
+            }
+            if self.current_stack().is_none() {
+                bail!("Not on a stack branch");
+            }
+            self.update_branches_from_remote(&known_remote_heads)?;
+            self.complete_step(Step::Pulled)?;
+        }
+        self.remove_merged_branches().await?;
+        if self.current_stack().is_none() {
+            println!("All branches of the stack were merged");
+            self.delete_merged_remote_branches()?;
+            return self.clear_sync_steps();
+        }
+        let branches = &self.current_stack().unwrap().branches.clone();
+        self.restack_branches(branches)?;
+        self.push_branches(branches)?;
+        let open_pulls = self.get_pull_requests().await?;
+        self.update_pr_bases(&open_pulls).await?;
+        self.delete_merged_remote_branches()?;
         let remote = self.repo.remote_repo_info()?;
-        let pulls = self.github.pulls(remote.owner, remote.name);
-        let open_pulls = pulls
-            .list()
-            .state(octocrab::params::State::Open)
-            .sort(Sort::Created)
-            .send()
-            .await?
-            .items;
-        let stack = &self.current_stack().unwrap();
-        let branches = &stack.branches;
-        let stack_pulls = branches
-            .iter()
-            .filter_map(|branch| self.get_branch_pr(&open_pulls, branch))
-            .collect::<Vec<PullRequest>>();
-        Ok(stack_pulls)
-    }
-
-    fn get_branch_pr(&self, pull_requests: &[PullRequest], branch: &String) -> Option<PullRequest> {
-        // First, check for exact SHA match (current implementation)
-        let exact_match = pull_requests
-            .iter()
-            .find(|pr| pr.head.sha == self.repo.head_sha(branch).unwrap_or("".to_string()))
-            .cloned();
-        
-        if exact_match.is_some() {
-            return exact_match;
-        }
-        
-        // If no exact match, check if the PR's head reference matches our branch name
-        // This handles cases where the local branch is ahead of the remote
-        let branch_name = branch.split('/').next_back().unwrap_or(branch);
-    
-       pull_requests
-            .iter()
-            .find(|pr| {
-                // Check if PR head ref matches our branch (ignoring repo prefix)
-                pr.head.ref_field.ends_with(branch_name) &&
-                // Verify branch name format to avoid false matches
-                (pr.head.ref_field == *branch || 
-                pr.head.ref_field.ends_with(&format!("/{}", branch_name)))
-            })
-            .cloned()
-    }
-
-    fn get_pr_branch(&self, pull_request: &PullRequest) -> Option<String> {
-        self.current_stack()?
+        let pulls = github.pulls(remote.owner, remote.name);
+        if update_descriptions {
+            self.update_pr_descriptions(&pulls, open_pulls).await?;
+        }
+        if share_stacks {
+            let stack = self.current_stack().unwrap();
+            shared_stacks::write(&self.repo, stack, None)?;
+            shared_stacks::push(&self.repo, "origin", &stack.id)?;
+        }
+        // The original branch might have been merged and removed from the stack
+        if self
+            .current_stack()
+            .unwrap()
             .branches
-            .iter()
-            .find(|branch| {
-                pull_request.head.sha == self.repo.head_sha(branch).unwrap_or("".to_string())
-            })
-            .cloned()
-    }
+            .contains(&current_branch.to_string())
+        {
+            self.repo.switch_branch(&current_branch)?;
+        }
+        self.clear_sync_steps()?;
 
-    async fn merge_pull_requests(&mut self) -> Result<()> {
-        let remote = self.repo.remote_repo_info()?;
-        let github = self.github.clone();
-        let pulls = github.pulls(remote.owner, remote.name);
-        let open_pulls = self.get_pull_requests().await?;
-        let base = self.current_stack().unwrap().base_branch.clone();
+        Ok(())
+    }
 
-        // TODO: fix for Squash and Rebase
-        // let merge_method = Select::with_theme(&ColorfulTheme::default())
-        //     .with_prompt("Merge method")
-        //     .default(0)
-        //     .items(&["Squash", "Merge", "Rebase"])
-        //     .interact()
-        //     .unwrap();
-        //
-        // let merge_method = match merge_method {
-        //     0 => MergeMethod::Squash,
-        //     1 => MergeMethod::Merge,
-        //     2 => MergeMethod::Rebase,
-        //     _ => panic!("Unknown merge method"),
-        // };
-        let merge_method = MergeMethod::Merge;
-
-        let mut orginal_branches = vec![];
-        for pr in &open_pulls {
-            pulls.update(pr.number).base(base.clone()).send().await?;
-            self.sync(false).await?;
-            println!("Merging PR #{}...", pr.number);
-            let branch = self.get_pr_branch(pr);
-            pulls.merge(pr.number).method(merge_method).send().await?;
-            // Not the best way to do this should try to listen to the completed merge somehow
-            sleep(Duration::from_secs(3)).await;
-            if let Some(branch) = branch {
-                orginal_branches.push(branch.clone());
-                self.remove_branch_from_stack(&branch)?;
+    /// Drops the stack branches whose PR was merged or whose commits are already part of the
+    /// base branch, e.g. after a squash merge through the github UI. The branches above are
+    /// moved onto the parent of the merged branch and restacked by the caller.
+    async fn remove_merged_branches(&mut self) -> Result<()> {
+        let stack = self.current_stack().unwrap().clone();
+        let base = match self.repo.has_remote_branch("origin", &stack.base_branch) {
+            true => format!("refs/remotes/origin/{}", stack.base_branch),
+            false => stack.base_branch.clone(),
+        };
+        let mut merged_prs = vec![];
+        if let (Some(github), Ok(remote)) = (&self.github, self.repo.remote_repo_info()) {
+            let pulls = github.pulls(&remote.owner, &remote.name);
+            for (branch, recorded) in &stack.pull_requests {
+                if pulls.get(recorded.number).await?.merged_at.is_some() {
+                    merged_prs.push(branch.clone());
+                }
             }
         }
 
-        println!("Sucessfully merged stack!");
-
-        let delete_branches = Confirm::new()
+        let mut merged = vec![];
+        for branch in &stack.branches {
+            let Some(parent) = stack.parent(branch) else {
+                continue;
+            };
+            if self.repo.head_sha(branch).is_err() {
+                continue;
+            }
+            let fork_point = self.fork_point(branch, parent)?;
+            if merged_prs.contains(branch)
+                || self.repo.is_merged_into(branch, &fork_point, &base)?
+            {
+                merged.push(branch.clone());
+            }
+        }
+        if merged.is_empty() {
+            return Ok(());
+        }
+
+        let delete_branches = self.config.delete_merged_branches.unwrap_or(false);
+        for branch in &merged {
+            println!("Branch {} was merged", style(branch).green());
+            let head = self.repo.head_sha(branch)?;
+            let children = self
+                .current_stack()
+                .map(|stack| stack.children(branch))
+                .unwrap_or_default();
+            self.remove_branch_from_stack(branch)?;
+            // The branches above only keep the commits they made on top of the merged branch
+            if let Some(stack) = self.current_stack_mut() {
+                for child in children {
+                    stack.fork_points.insert(child, head.clone());
+                }
+            }
+            self.state.write(self.gstack_dir.clone())?;
+            self.complete_step(Step::DetectedMerged(branch.clone()))?;
+
+            if !delete_branches {
+                continue;
+            }
+            if let Some(path) = self.repo.other_worktree(branch)? {
+                println!(
+                    "Not deleting branch {}, it is checked out in {}",
+                    branch,
+                    path.display()
+                );
+            } else {
+                // Squash merged branches are not ancestors of the base branch
+                self.repo.cmd(["branch", "-D", branch.as_str()])?;
+                println!("Deleted branch {}", branch);
+            }
+        }
+        Ok(())
+    }
+
+    /// Deletes the remote branches of the branches the sync found merged. Runs after
+    /// the PR's above them were retargeted, github closes PR's whose base branch is deleted.
+    fn delete_merged_remote_branches(&mut self) -> Result<()> {
+        if !self.config.delete_merged_branches.unwrap_or(false) {
+            return Ok(());
+        }
+        let merged: Vec<String> = self
+            .journal
+            .iter()
+            .flat_map(|journal| &journal.completed)
+            .filter_map(|step| match step {
+                Step::DetectedMerged(branch)
+                    if !self.is_step_completed(&Step::DeletedRemoteBranch(branch.clone())) =>
+                {
+                    Some(branch.clone())
+                }
+                _ => None,
+            })
+            .collect();
+        for branch in merged {
+            if self.repo.has_remote_branch("origin", &branch) {
+                match self
+                    .repo
+                    .cmd(["push", "origin", "--delete", branch.as_str()])
+                {
+                    Ok(()) => println!("Deleted remote branch {}", branch),
+                    Err(err) => println!("Could not delete remote branch {}: {}", branch, err),
+                }
+            }
+            self.complete_step(Step::DeletedRemoteBranch(branch))?;
+        }
+        Ok(())
+    }
+
+    /// Remote heads of all stack and base branches as gstack last saw them, falling back to
+    /// the remote tracking branches for branches that weren't synced yet
+    fn known_remote_heads(&self) -> BTreeMap<String, String> {
+        let mut known = self.state.remote_heads.clone();
+        for stack in &self.state.stacks {
+            for branch in std::iter::once(&stack.base_branch).chain(&stack.branches) {
+                if known.contains_key(branch) {
+                    continue;
+                }
+                let remote_ref = format!("refs/remotes/origin/{}", branch);
+                if let Ok(sha) = self.repo.head_sha(&remote_ref) {
+                    known.insert(branch.clone(), sha);
+                }
+            }
+        }
+        known
+    }
+
+    /// Fetches all branches of the remote, and the shared stacks if enabled, in a single fetch
+    fn fetch_remote(&self, share_stacks: bool) -> Result<()> {
+        let mut refspecs = vec!["+refs/heads/*:refs/remotes/origin/*".to_string()];
+        if share_stacks {
+            refspecs.push(shared_stacks::fetch_refspec());
+        }
+        let mut args = vec!["fetch", "--prune", "origin"];
+        args.extend(refspecs.iter().map(|refspec| refspec.as_str()));
+        self.repo.cmd(args)?;
+        Ok(())
+    }
+
+    /// Moves the base and stack branches to their fetched remote heads where that is a fast
+    /// forward. Fails if a stack branch and its remote diverged because someone else pushed to
+    /// it, branches that were only rewritten locally, e.g. by `gs restack`, are pushed later.
+    fn update_branches_from_remote(
+        &mut self,
+        known_remote_heads: &BTreeMap<String, String>,
+    ) -> Result<()> {
+        let stack = self.current_stack().unwrap().clone();
+        let mut diverged = vec![];
+        for branch in std::iter::once(&stack.base_branch).chain(&stack.branches) {
+            let remote_ref = format!("refs/remotes/origin/{}", branch);
+            let Ok(remote) = self.repo.head_sha(&remote_ref) else {
+                // The branch was deleted on the remote, the next push creates it again
+                self.state.remote_heads.remove(branch);
+                continue;
+            };
+            match self.repo.head_sha(branch) {
+                Err(_) => {
+                    self.repo
+                        .cmd(["branch", branch.as_str(), remote_ref.as_str()])?;
+                    println!("Created branch {} from the remote", style(branch).green());
+                }
+                Ok(local) if local == remote || self.repo.is_ancestor(&remote, &local) => {}
+                Ok(local) if self.repo.is_ancestor(&local, &remote) => {
+                    self.fast_forward_branch(branch, &remote)?;
+                    println!("Updated branch {} from the remote", style(branch).green());
+                }
+                Ok(_) if known_remote_heads.get(branch) == Some(&remote) => {}
+                Ok(_) if *branch == stack.base_branch => {
+                    println!(
+                        "Base branch {} diverged from the remote, restacking onto the local branch",
+                        style(branch).yellow()
+                    );
+                    continue;
+                }
+                Ok(_) => {
+                    diverged.push(branch.clone());
+                    continue;
+                }
+            }
+            self.state.remote_heads.insert(branch.clone(), remote);
+        }
+        self.state.write(self.gstack_dir.clone())?;
+        if !diverged.is_empty() {
+            bail!(
+                "Someone else pushed to {} since the last sync. Rebase the local branches onto the remote ones (git rebase origin/<branch>) or reset them to the remote (git reset --hard origin/<branch>) and run {}.",
+                diverged.join(", "),
+                style("gs continue").green()
+            );
+        }
+        Ok(())
+    }
+
+    /// Fetches the stacks shared by teammates and merges them into the local state. Stacks that
+    /// are not known locally are imported if one of their branches exists locally.
+    fn pull_shared_stacks(&mut self) -> Result<()> {
+        for (id, remote_commit) in shared_stacks::list_remote(&self.repo)? {
+            let remote_stack = shared_stacks::read(&self.repo, &remote_commit)?;
+            let name = remote_stack.name();
+            let Some(idx) = self.state.stacks.iter().position(|s| s.id == id) else {
+                let current_branch = self.repo.current_branch()?.to_string();
+                // Branches that are tracked by a local stack with another id are left alone
+                let tracked = remote_stack.branches.iter().any(|branch| {
+                    self.state
+                        .stacks
+                        .iter()
+                        .any(|s| s.branches.contains(branch))
+                });
+                if remote_stack.branches.contains(&current_branch) && !tracked {
+                    println!("Imported shared stack {}", style(&name).cyan());
+                    self.state.stacks.push(remote_stack);
+                    shared_stacks::fast_forward(&self.repo, &id, &remote_commit)?;
+                }
+                continue;
+            };
+
+            // Record local changes since the last shared version before comparing
+            let local_commit = shared_stacks::write(&self.repo, &self.state.stacks[idx], None)?;
+            if local_commit == remote_commit || self.repo.is_ancestor(&remote_commit, &local_commit)
+            {
+                continue;
+            }
+            if self.repo.is_ancestor(&local_commit, &remote_commit) {
+                self.state.stacks[idx] = remote_stack;
+                shared_stacks::fast_forward(&self.repo, &id, &remote_commit)?;
+            } else {
+                println!(
+                    "Merging diverged shared stack {}, keeping local changes",
+                    style(&name).cyan()
+                );
+                let merged = shared_stacks::merge(&self.state.stacks[idx], &remote_stack);
+                shared_stacks::write(&self.repo, &merged, Some(&remote_commit))?;
+                self.state.stacks[idx] = merged;
+            }
+        }
+
+        // Branches of a teammate's stack might not exist locally yet
+        let branches: Vec<String> = self
+            .state
+            .stacks
+            .iter()
+            .flat_map(|stack| [vec![stack.base_branch.clone()], stack.branches.clone()].concat())
+            .collect();
+        for branch in branches {
+            let remote_ref = format!("refs/remotes/origin/{}", branch);
+            if self.repo.head_sha(&branch).is_err() && self.repo.head_sha(&remote_ref).is_ok() {
+                self.repo
+                    .cmd(["branch", branch.as_str(), remote_ref.as_str()])?;
+            }
+        }
+        self.state.write(self.gstack_dir.clone())
+    }
+
+    /// Force pushes the given stack branches that changed in a single atomic push, so the remote
+    /// is either fully updated or untouched. A branch is only overwritten if the remote is still
+    /// at the head gstack last fetched or pushed. Updates the head recorded for their PR's.
+    fn push_branches(&mut self, branches: &[String]) -> Result<()> {
+        let mut leases = vec![];
+        let mut refspecs = vec![];
+        let mut pushed = vec![];
+        for branch in branches {
+            if self.is_step_completed(&Step::Pushed(branch.clone())) {
+                continue;
+            }
+            let local = self.repo.head_sha(branch)?;
+            let remote_ref = format!("refs/remotes/origin/{}", branch);
+            let expected = match self.state.remote_heads.get(branch) {
+                Some(expected) => Some(expected.clone()),
+                None => self.repo.head_sha(&remote_ref).ok(),
+            };
+            if expected.as_ref() == Some(&local) {
+                continue;
+            }
+            // An empty lease only allows creating the branch
+            leases.push(format!(
+                "--force-with-lease=refs/heads/{}:{}",
+                branch,
+                expected.unwrap_or_default()
+            ));
+            refspecs.push(format!("refs/heads/{}:refs/heads/{}", branch, branch));
+            pushed.push(branch.clone());
+        }
+        if !pushed.is_empty() {
+            let mut args = vec!["push", "--atomic", "--set-upstream", "origin"];
+            args.extend(leases.iter().chain(&refspecs).map(|arg| arg.as_str()));
+            self.repo.cmd(args).map_err(|err| {
+                anyhow!(
+                    "Pushing {} failed, the remote was not changed. If someone else pushed to these branches, run {} and {} again to fetch their changes.\n{}",
+                    pushed.join(", "),
+                    style("gs abort").green(),
+                    style("gs sync").green(),
+                    err
+                )
+            })?;
+            println!(
+                "Pushed {}",
+                pushed
+                    .iter()
+                    .map(|branch| style(branch).green().to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+        }
+
+        for branch in branches {
+            if !self.is_step_completed(&Step::Pushed(branch.clone())) {
+                self.complete_step(Step::Pushed(branch.clone()))?;
+            }
+            let head_sha = self.repo.head_sha(branch)?;
+            self.state
+                .remote_heads
+                .insert(branch.clone(), head_sha.clone());
+            // Not necessarily the current stack, e.g. when gs doctor pushes a PR base
+            if let Some(recorded) = self
+                .state
+                .stacks
+                .iter_mut()
+                .find(|stack| stack.branches.contains(branch))
+                .and_then(|stack| stack.pull_requests.get_mut(branch))
+            {
+                recorded.head_sha = head_sha;
+            }
+        }
+        self.state.write(self.gstack_dir.clone())
+    }
+
+    async fn start_restack(&mut self, upstack: bool) -> Result<()> {
+        let Some((stack, idx)) = self.current_stack_position() else {
+            bail!("Not on a stack branch");
+        };
+        let from = match upstack {
+            true => Some(stack.branches[idx].clone()),
+            false => None,
+        };
+        self.run_operation(Operation::Restack { from }).await
+    }
+
+    fn restack(&mut self, from: &Option<String>) -> Result<()> {
+        let current_branch = self.repo.current_branch()?;
+        let stack = self.current_stack().unwrap();
+        let branches = match from {
+            Some(from) => stack.upstack(from),
+            None => stack.branches.clone(),
+        };
+        self.restack_branches(&branches)?;
+        self.repo.switch_branch(&current_branch)?;
+        println!("Restacked {} branches", branches.len());
+        Ok(())
+    }
+
+    /// Rebases the given stack branches onto their parent branch, parents have to come first.
+    /// Only touches local branches, pulling and pushing is left to the caller.
+    fn restack_branches(&mut self, branches: &[String]) -> Result<()> {
+        for branch in branches {
+            let Some(parent) = self.current_stack().unwrap().parent(branch).cloned() else {
+                continue;
+            };
+            if !self.is_step_completed(&Step::Rebased(branch.clone())) {
+                self.rebase_on_parent(branch, &parent)?;
+                self.complete_step(Step::Rebased(branch.clone()))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Lets the user reorder the branches of the current stack in their editor
+    async fn start_reorder(&mut self) -> Result<()> {
+        let Some(stack) = self.current_stack() else {
+            bail!("Not on a stack branch");
+        };
+        if !stack.is_linear() {
+            bail!("Only stacks without branches that have several children can be reordered");
+        }
+        let branches = stack.branches.clone();
+        let path = self.gstack_dir.join(REORDER_FILE);
+        let mut contents = branches.join("\n");
+        contents.push_str(
+            "\n\n# Reorder the branches of the stack, the first line is the bottom branch.\n\
+             # Lines starting with '#' are ignored, removing branches is not supported.\n",
+        );
+        fs::write(&path, contents)?;
+        self.repo.run_editor(&path)?;
+        let edited = fs::read_to_string(&path)?;
+        fs::remove_file(&path)?;
+
+        let reordered: Vec<String> = edited
+            .lines()
+            .map(|line| line.trim())
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| line.to_string())
+            .collect();
+        let mut sorted = reordered.clone();
+        sorted.sort();
+        let mut expected = branches.clone();
+        expected.sort();
+        if sorted != expected {
+            bail!("The reordered stack has to contain every stack branch exactly once");
+        }
+        if reordered == branches {
+            println!("Stack order unchanged");
+            return Ok(());
+        }
+        self.run_operation(Operation::Reorder {
+            branches: reordered,
+        })
+        .await
+    }
+
+    /// Rebases every branch onto its new parent and updates the PR's to the new order
+    async fn reorder(&mut self, branches: &[String]) -> Result<()> {
+        let current_branch = self.repo.current_branch()?;
+        let stack = self.current_stack_mut().unwrap();
+        stack.branches = branches.to_vec();
+        stack.parents.clear();
+        stack.record_linear_parents();
+        self.state.write(self.gstack_dir.clone())?;
+        self.restack_branches(branches)?;
+        if self.github.is_some() {
+            self.push_branches(branches)?;
+            let open_pulls = self.get_pull_requests().await?;
+            self.update_pr_bases(&open_pulls).await?;
+            let remote = self.repo.remote_repo_info()?;
+            let github = self.github()?;
+            let pulls = github.pulls(remote.owner, remote.name);
+            self.update_pr_descriptions(&pulls, open_pulls).await?;
+        }
+        self.repo.switch_branch(&current_branch)?;
+        self.clear_sync_steps()?;
+        println!("Reordered stack");
+        Self::list_stack_branches(self.current_stack().unwrap())
+    }
+
+    async fn start_fold(&mut self) -> Result<()> {
+        let Some((stack, idx)) = self.current_stack_position() else {
+            bail!("Not on a stack branch");
+        };
+        let branch = stack.branches[idx].clone();
+        let into = stack.parent(&branch).unwrap().clone();
+        if into == stack.base_branch {
+            bail!("The bottom branch of a stack can not be folded into the base branch");
+        }
+        let (mut folded_pr, mut surviving_pr) = (None, None);
+        if self.github.is_some() {
+            self.get_pull_requests().await?;
+            let pull_requests = &self.current_stack().unwrap().pull_requests;
+            folded_pr = pull_requests.get(&branch).map(|pr| pr.number);
+            surviving_pr = pull_requests.get(&into).map(|pr| pr.number);
+        }
+        self.run_operation(Operation::Fold {
+            branch,
+            into,
+            folded_pr,
+            surviving_pr,
+        })
+        .await
+    }
+
+    /// Moves the commits of `branch` into the branch below it and removes it from the stack.
+    /// The folded PR is closed only after the PR above it was retargeted, as deleting its
+    /// remote branch would close the PR above otherwise.
+    async fn fold(
+        &mut self,
+        branch: &String,
+        into: &String,
+        folded_pr: Option<u64>,
+        surviving_pr: Option<u64>,
+    ) -> Result<()> {
+        if !self.is_step_completed(&Step::Folded(branch.clone())) {
+            if !self.is_step_completed(&Step::Rebased(branch.clone())) {
+                self.rebase_on_parent(branch, into)?;
+                self.complete_step(Step::Rebased(branch.clone()))?;
+            }
+            let head = self.repo.head_sha(branch)?;
+            self.repo.switch_branch(&BranchName::from_str(into)?)?;
+            self.repo.cmd(["merge", "--ff-only", head.as_str()])?;
+
+            let stack = self.current_stack_mut().unwrap();
+            for child in stack.children(branch) {
+                stack.fork_points.insert(child, head.clone());
+            }
+            stack.remove_branch(branch);
+            self.state.write(self.gstack_dir.clone())?;
+            self.complete_step(Step::Folded(branch.clone()))?;
+        }
+
+        let stack = self.current_stack().unwrap();
+        let branches = stack.branches.clone();
+        self.restack_branches(&stack.upstack(into)[1..])?;
+
+        if self.github.is_some() {
+            self.push_branches(&branches)?;
+            let open_pulls = self.get_pull_requests().await?;
+            self.update_pr_bases(&open_pulls).await?;
+            let remote = self.repo.remote_repo_info()?;
+            let github = self.github()?;
+            let pulls = github.pulls(&remote.owner, &remote.name);
+            self.update_pr_descriptions(&pulls, open_pulls).await?;
+
+            if let Some(number) = folded_pr {
+                if !self.is_step_completed(&Step::ClosedPullRequest(number)) {
+                    let comment = match surviving_pr {
+                        Some(surviving) => format!("Folded into #{}", surviving),
+                        None => format!("Folded into {}", into),
+                    };
+                    github
+                        .issues(&remote.owner, &remote.name)
+                        .create_comment(number, comment)
+                        .await?;
+                    pulls
+                        .update(number)
+                        .state(octocrab::params::pulls::State::Closed)
+                        .send()
+                        .await?;
+                    println!("Closed PR #{}", number);
+                    self.complete_step(Step::ClosedPullRequest(number))?;
+                }
+            }
+            if self.repo.has_remote_branch("origin", branch) {
+                self.repo
+                    .cmd(["push", "origin", "--delete", branch.as_str()])?;
+                println!("Deleted remote branch {}", branch);
+            }
+        }
+
+        if !self.is_step_completed(&Step::DeletedBranch(branch.clone())) {
+            self.repo.cmd(["branch", "-D", branch.as_str()])?;
+            self.complete_step(Step::DeletedBranch(branch.clone()))?;
+        }
+        self.repo.switch_branch(&BranchName::from_str(into)?)?;
+        self.clear_sync_steps()?;
+        println!(
+            "Folded {} into {}",
+            style(branch).green(),
+            style(into).green()
+        );
+        Ok(())
+    }
+
+    /// Commit of the parent the branch was built on, the branch's own commits are the ones after it
+    fn fork_point(&self, branch: &str, parent: &str) -> Result<String> {
+        let recorded = self
+            .current_stack()
+            .and_then(|stack| stack.fork_points.get(branch))
+            .filter(|fork_point| self.repo.is_ancestor(fork_point, branch))
+            .cloned();
+        // Branches created before fork points were recorded fall back to the merge base
+        match recorded {
+            Some(fork_point) => Ok(fork_point),
+            None => self.repo.merge_base(parent, branch),
+        }
+    }
+
+    /// Splits the current branch into several stacked branches at the selected commits.
+    /// The new branches are inserted below the current branch, which keeps the topmost commits.
+    fn split(&mut self, by_commit: bool) -> Result<()> {
+        let Some((stack, idx)) = self.current_stack_position() else {
+            bail!("Not on a stack branch");
+        };
+        let branch = stack.branches[idx].clone();
+        let parent = stack.parent(&branch).unwrap().clone();
+        let prefix = stack.prefix.clone();
+        let fork_point = self.fork_point(&branch, &parent)?;
+        let range = format!("{}..{}", fork_point, branch);
+        let commits: Vec<(String, String)> = self
+            .repo
+            .cmd_out(["log", "--reverse", "--format=%H %s", range.as_str()])?
+            .iter()
+            .filter_map(|line| line.split_once(' '))
+            .map(|(sha, subject)| (sha.to_string(), subject.to_string()))
+            .collect();
+        if commits.len() < 2 {
+            bail!("{} needs at least two commits to be split", branch);
+        }
+
+        // The last commit always stays on the current branch
+        let candidates = &commits[..commits.len() - 1];
+        let boundaries: Vec<usize> = match by_commit {
+            true => (0..candidates.len()).collect(),
+            false => {
+                let items: Vec<String> = candidates
+                    .iter()
+                    .map(|(sha, subject)| format!("{} {}", &sha[..7], subject))
+                    .collect();
+                MultiSelect::with_theme(&ColorfulTheme::default())
+                    .with_prompt("Select the last commit of each new branch (oldest first)")
+                    .items(&items)
+                    .interact()
+                    .unwrap()
+            }
+        };
+        if boundaries.is_empty() {
+            println!("No commits selected, nothing to split");
+            return Ok(());
+        }
+
+        let name = branch.split('/').next_back().unwrap().to_string();
+        let mut new_branches = vec![];
+        for (i, boundary) in boundaries.iter().enumerate() {
+            let (sha, subject) = &commits[*boundary];
+            let default_name = format!("{}-{}", name, i + 1);
+            let name_val = match by_commit {
+                true => default_name,
+                false => Input::with_theme(&ColorfulTheme::default())
+                    .with_prompt(format!("Branch name for commits up to \"{}\":", subject))
+                    .default(default_name)
+                    .interact_text()
+                    .unwrap(),
+            };
+            new_branches.push((GsContext::get_branch_name(&prefix, &name_val)?, sha.clone()));
+        }
+
+        backup::create(&self.repo, "split", &self.state)?;
+        for (new_branch, sha) in &new_branches {
+            self.repo
+                .cmd(["branch", new_branch.to_string().as_str(), sha.as_str()])?;
+        }
+        let stack = self.current_stack_mut().unwrap();
+        let mut fork_point = fork_point;
+        let mut parent = parent;
+        for (i, (new_branch, sha)) in new_branches.iter().enumerate() {
+            stack.branches.insert(idx + i, new_branch.to_string());
+            stack.parents.insert(new_branch.to_string(), parent);
+            stack
+                .fork_points
+                .insert(new_branch.to_string(), fork_point.clone());
+            parent = new_branch.to_string();
+            fork_point = sha.clone();
+        }
+        stack.parents.insert(branch.clone(), parent);
+        stack.fork_points.insert(branch.clone(), fork_point);
+        self.state.write(self.gstack_dir.clone())?;
+
+        for (new_branch, _) in &new_branches {
+            println!("Created branch {}", style(new_branch).green());
+        }
+        println!(
+            "Split {} into {} branches, run {} to create their PR's",
+            branch,
+            new_branches.len() + 1,
+            style("gs pr new").green()
+        );
+        Ok(())
+    }
+
+    /// Moves the commits a branch made on top of its recorded fork point onto the current head
+    /// of its parent, so that amended or squash merged parent commits are not replayed
+    fn rebase_on_parent(&mut self, branch: &str, parent: &str) -> Result<()> {
+        let parent_head = self.repo.head_sha(parent)?;
+        let fork_point = self.fork_point(branch, parent)?;
+        // Nothing to do if the branch already builds on the current head of its parent
+        let up_to_date = self.repo.is_ancestor(&fork_point, &parent_head)
+            && self.repo.is_ancestor(&parent_head, branch);
+        if !up_to_date {
+            // Branches checked out in another worktree can only be rebased there
+            let worktree = self.worktree_repo(branch)?;
+            let repo = worktree.as_ref().unwrap_or(&self.repo);
+            repo.rebase_onto(
+                BranchName::from_str(branch)?,
+                BranchName::from_str(parent)?,
+                &fork_point,
+            )?;
+        }
+        if let Some(stack) = self.current_stack_mut() {
+            stack
+                .fork_points
+                .insert(branch.to_string(), parent_head.clone());
+        }
+        self.state.write(self.gstack_dir.clone())
+    }
+
+    async fn create_pull_requests(&mut self) -> Result<()> {
+        self.start_sync(false).await?;
+        let open_pulls = self.get_pull_requests().await?;
+        let stack = self.current_stack().unwrap().clone();
+        let branches = stack.branches.clone();
+        let prefix = stack.name();
+        let recorded_pulls = stack.pull_requests.clone();
+        let remote = self.repo.remote_repo_info()?;
+        let github = self.github()?;
+        let pulls = github.pulls(remote.owner, remote.name);
+
+        let draft = Confirm::new()
+            .with_prompt("Create as draft?")
+            .interact()
+            .unwrap();
+
+        let mut created_pulls = vec![];
+        for (i, branch) in branches.iter().enumerate() {
+            let existing_pr = recorded_pulls
+                .get(branch)
+                .and_then(|recorded| open_pulls.iter().find(|pr| pr.number == recorded.number));
+            if let Some(pr) = existing_pr {
+                created_pulls.push(pr.clone());
+                continue;
+            }
+            let base = stack.parent(branch).unwrap();
+
+            let title = format!(
+                "{} (#{}) - {}",
+                prefix,
+                i,
+                branch.split('/').next_back().unwrap()
+            );
+
+            println!("base: {}, title: {}", base, title);
+            let pr = pulls
+                .create(title, branch, base)
+                .draft(draft)
+                .body("---")
+                .send()
+                .await?;
+            println!(
+                "#{}: {}",
+                pr.number,
+                style(pr.html_url.clone().unwrap()).blue()
+            );
+            self.record_pull_request(branch, &pr);
+            self.state.write(self.gstack_dir.clone())?;
+            created_pulls.push(pr);
+        }
+
+        self.update_pr_descriptions(&pulls, created_pulls).await?;
+        Ok(())
+    }
+
+    /// Points the base of every given stack PR at the branch below it, pushing the parent
+    /// branch first if needed
+    async fn update_pr_bases(&mut self, prs: &[PullRequest]) -> Result<()> {
+        let stack = self.current_stack().unwrap().clone();
+        let remote = self.repo.remote_repo_info()?;
+        let github = self.github()?;
+        let pulls = github.pulls(remote.owner, remote.name);
+        for pr in prs {
+            let Some(branch) = self.get_pr_branch(pr) else {
+                continue;
+            };
+            let Some(parent) = stack.parent(&branch) else {
+                continue;
+            };
+            if pr.base.ref_field == *parent {
+                continue;
+            }
+            if *parent != stack.base_branch {
+                self.push_branches(std::slice::from_ref(parent))?;
+            }
+            pulls.update(pr.number).base(parent).send().await?;
+            println!(
+                "Changed base of PR #{} to {}",
+                pr.number,
+                style(parent).cyan()
+            );
+        }
+        Ok(())
+    }
+
+    async fn update_pr_descriptions(
+        &self,
+        pulls: &PullRequestHandler<'_>,
+        prs: Vec<PullRequest>,
+    ) -> Result<()> {
+        for pr in prs.iter() {
+            let mut body = pr
+                .body
+                .clone()
+                .unwrap_or("".to_string())
+                .lines()
+                .take_while(|line| !line.contains("---"))
+                .collect::<Vec<&str>>()
+                .join("\n");
+
+            body.push_str("\n---\n");
+            prs.iter().rev().for_each(|p| {
+                body.push_str(format!("- #{}", p.number).as_str());
+                if pr.number == p.number {
+                    body.push_str(" (This PR)");
+                }
+                body.push('\n');
+            });
+            body = body.clone() + "\n**Created by [gstack](https://github.com/Bendzae/gstack)**";
+
+            pulls.update(pr.number).body(body).send().await?;
+            println!("Updated description for PR: #{}", pr.number);
+        }
+        Ok(())
+    }
+
+    async fn list_pull_requests(&mut self) -> Result<()> {
+        let open_pulls = self.get_pull_requests().await?;
+        for pr in open_pulls.iter() {
+            println!("#{}: {} ", pr.number, pr.html_url.clone().unwrap());
+        }
+        Ok(())
+    }
+
+    /// Lists all open PR's of the remote repository, following pagination
+    async fn get_open_pull_requests(&self) -> Result<Vec<PullRequest>> {
+        let remote = self.repo.remote_repo_info()?;
+        let github = self.github()?;
+        let pulls = github.pulls(remote.owner, remote.name);
+        let first_page = pulls
+            .list()
+            .state(octocrab::params::State::Open)
+            .sort(Sort::Created)
+            .per_page(100)
+            .send()
+            .await?;
+        Ok(github.all_pages(first_page).await?)
+    }
+
+    /// Returns the open PR's of the current stack in stack order.
+    /// PR's are looked up by the number recorded in the state. Branches without a recorded
+    /// (open) PR are matched against the open PR's of the repository by their exact head ref,
+    /// and any PR found this way is recorded for subsequent lookups.
+    async fn get_pull_requests(&mut self) -> Result<Vec<PullRequest>> {
+        let remote = self.repo.remote_repo_info()?;
+        let github = self.github()?;
+        let pulls = github.pulls(remote.owner, remote.name);
+        let stack = self.current_stack().unwrap();
+        let branches = stack.branches.clone();
+        let recorded_pulls = stack.pull_requests.clone();
+
+        let mut open_pulls: Option<Vec<PullRequest>> = None;
+        let mut stack_pulls = vec![];
+        for branch in &branches {
+            if let Some(recorded) = recorded_pulls.get(branch) {
+                let pr = pulls.get(recorded.number).await?;
+                if pr.state == Some(IssueState::Open) {
+                    self.record_pull_request(branch, &pr);
+                    stack_pulls.push(pr);
+                    continue;
+                }
+            }
+            if open_pulls.is_none() {
+                open_pulls = Some(self.get_open_pull_requests().await?);
+            }
+            let discovered = open_pulls
+                .as_deref()
+                .and_then(|open_pulls| pr_status::pull_request_of(open_pulls, branch))
+                .cloned();
+            if let Some(pr) = discovered {
+                self.record_pull_request(branch, &pr);
+                stack_pulls.push(pr);
+            }
+        }
+        self.state.write(self.gstack_dir.clone())?;
+        Ok(stack_pulls)
+    }
+
+    fn record_pull_request(&mut self, branch: &str, pr: &PullRequest) {
+        let Some(stack) = self
+            .state
+            .stacks
+            .iter_mut()
+            .find(|stack| stack.branches.iter().any(|b| b == branch))
+        else {
+            return;
+        };
+        stack.pull_requests.insert(
+            branch.to_string(),
+            StackPullRequest {
+                number: pr.number,
+                url: pr
+                    .html_url
+                    .as_ref()
+                    .map(|url| url.to_string())
+                    .unwrap_or_default(),
+                head_sha: pr.head.sha.clone(),
+            },
+        );
+    }
+
+    fn get_pr_branch(&self, pull_request: &PullRequest) -> Option<String> {
+        self.current_stack()?
+            .pull_requests
+            .iter()
+            .find(|(_, recorded)| recorded.number == pull_request.number)
+            .map(|(branch, _)| branch.clone())
+    }
+
+    async fn start_merge(&mut self, method: &Option<MergeStrategy>) -> Result<()> {
+        if self.current_stack().is_none() {
+            bail!("Not on a stack branch");
+        }
+        self.github()?;
+        let strategy = method
+            .or(self.config.merge_method)
+            .unwrap_or(MergeStrategy::Merge);
+        self.run_operation(Operation::Merge { strategy }).await
+    }
+
+    async fn merge_pull_requests(&mut self, strategy: MergeStrategy) -> Result<()> {
+        let remote = self.repo.remote_repo_info()?;
+        let github = self.github()?;
+        let pulls = github.pulls(&remote.owner, &remote.name);
+        let base = self.current_stack().unwrap().base_branch.clone();
+        let timeout = Duration::from_secs(self.config.merge_timeout_secs.unwrap_or(600));
+
+        let merge_method = match strategy {
+            MergeStrategy::Merge => MergeMethod::Merge,
+            MergeStrategy::Squash => MergeMethod::Squash,
+            MergeStrategy::Rebase => MergeMethod::Rebase,
+        };
+
+        // Finish up PR's that were merged before the operation got interrupted
+        for (number, branch, head) in self.merged_pull_requests() {
+            self.finish_merge(number, &branch, &head, &base, strategy)?;
+        }
+
+        let open_pulls = self.get_pull_requests().await?;
+        for pr in &open_pulls {
+            pulls.update(pr.number).base(base.clone()).send().await?;
+            self.sync(false).await?;
+            let branch = self.get_pr_branch(pr);
+            let merged_head = branch
+                .as_ref()
+                .map(|branch| self.repo.head_sha(branch))
+                .transpose()?;
+            pr_status::wait_until_mergeable(
+                &github,
+                &remote,
+                pr.number,
+                merged_head.as_deref(),
+                timeout,
+            )
+            .await?;
+            println!("Merging PR #{}...", pr.number);
+            // GitHub refuses the merge if the head moved since the checks passed
+            let mut merge = pulls.merge(pr.number).method(merge_method);
+            if let Some(head) = &merged_head {
+                merge = merge.sha(head.clone());
+            }
+            let merge = merge.send().await?;
+            if !merge.merged {
+                bail!(
+                    "GitHub did not merge PR #{}: {}",
+                    pr.number,
+                    merge.message.unwrap_or_default()
+                );
+            }
+            pr_status::wait_until_merged(&github, &remote, pr.number, timeout).await?;
+            if let (Some(branch), Some(head)) = (branch, merged_head) {
+                self.complete_step(Step::Merged {
+                    number: pr.number,
+                    branch: branch.clone(),
+                    head: head.clone(),
+                })?;
+                self.finish_merge(pr.number, &branch, &head, &base, strategy)?;
+            }
+        }
+
+        println!("Sucessfully merged stack!");
+
+        let delete_branches = Confirm::new()
             .with_prompt("Delete local branches?")
             .interact()
             .unwrap();
 
         if delete_branches {
-            for branch in &orginal_branches {
-                self.repo.cmd(&["branch", "-d", branch.as_str()])?;
+            // Squashed or rebased branches are not ancestors of the base branch
+            let delete_flag = match strategy {
+                MergeStrategy::Merge => "-d",
+                _ => "-D",
+            };
+            for (_, branch, _) in &self.merged_pull_requests() {
+                self.repo.cmd(["branch", delete_flag, branch.as_str()])?;
                 println!("Deleted branch {}", branch);
             }
         }
         Ok(())
     }
 
+    /// Removes a merged branch from the stack and moves the remaining branches onto the base
+    fn finish_merge(
+        &mut self,
+        number: u64,
+        branch: &String,
+        merged_head: &str,
+        base: &str,
+        strategy: MergeStrategy,
+    ) -> Result<()> {
+        if self.is_step_completed(&Step::MergeFinished(number)) {
+            return Ok(());
+        }
+        let children = self
+            .current_stack()
+            .map(|stack| stack.children(branch))
+            .unwrap_or_default();
+        self.remove_branch_from_stack(branch)?;
+        // Squash and rebase merges rewrite the merged commits on the base branch, so the
+        // remaining branches are moved onto the new base, dropping the already merged commits
+        if strategy != MergeStrategy::Merge {
+            self.restack_onto_merged_base(base, merged_head, &children)?;
+        }
+        self.complete_step(Step::MergeFinished(number))
+    }
+
+    /// Number, branch and head of every PR merged by the running operation
+    fn merged_pull_requests(&self) -> Vec<(u64, String, String)> {
+        let Some(journal) = &self.journal else {
+            return vec![];
+        };
+        journal
+            .completed
+            .iter()
+            .filter_map(|step| match step {
+                Step::Merged {
+                    number,
+                    branch,
+                    head,
+                } => Some((*number, branch.clone(), head.clone())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Updates the local base branch from the remote and rebases the remaining stack branches
+    /// onto it, replaying only the commits the children of the merged branch made on top of it
+    fn restack_onto_merged_base(
+        &mut self,
+        base: &str,
+        merged_head: &str,
+        children: &[String],
+    ) -> Result<()> {
+        let Some(stack) = self.current_stack_mut() else {
+            return Ok(());
+        };
+        let branches = stack.branches.clone();
+        for child in children {
+            stack
+                .fork_points
+                .insert(child.clone(), merged_head.to_string());
+        }
+        let current_branch = self.repo.current_branch()?;
+        // git fetch refuses to update the base branch while it is checked out in any worktree
+        self.repo.cmd(["fetch", "origin", base])?;
+        let remote_base = format!("refs/remotes/origin/{}", base);
+        let local_base = self.repo.head_sha(base)?;
+        let remote_head = self.repo.head_sha(&remote_base)?;
+        if local_base != remote_head && self.repo.is_ancestor(&local_base, &remote_head) {
+            self.fast_forward_branch(base, &remote_head)?;
+        }
+        self.restack_branches(&branches)?;
+        self.push_branches(&branches)?;
+        self.repo.switch_branch(&current_branch)?;
+        self.clear_sync_steps()
+    }
+
     fn remove_branch_from_stack(&mut self, branch: &String) -> Result<()> {
         println!("Removing branch: {}", branch);
         if !self.current_stack().unwrap().branches.contains(branch) {
@@ -536,14 +1966,17 @@ impl GsContext {
             return Ok(());
         };
 
-        let branch_idx = self.state.stacks[stack_idx]
-            .branches
-            .iter()
-            .position(|b| b == branch)
-            .unwrap();
-        self.state.stacks[stack_idx].branches.remove(branch_idx);
+        let stack = &mut self.state.stacks[stack_idx];
+        // The branches above now build on the removed branch's fork point, which keeps the
+        // removed branch's commits in them until they are restacked
+        if let Some(fork_point) = stack.fork_points.get(branch).cloned() {
+            for child in stack.children(branch) {
+                stack.fork_points.insert(child, fork_point.clone());
+            }
+        }
+        stack.remove_branch(branch);
 
-        self.state.write(self.base_path.clone())?;
+        self.state.write(self.gstack_dir.clone())?;
         // Checkout another stack branch or base if the current branch was deleted
         if &current_branch == branch {
             let base = self.state.stacks[stack_idx].base_branch.clone();
@@ -557,31 +1990,371 @@ impl GsContext {
 
         if self.state.stacks[stack_idx].branches.is_empty() {
             self.state.stacks.remove(stack_idx);
-            self.state.write(self.base_path.clone())?;
+            self.state.write(self.gstack_dir.clone())?;
         }
         println!("Removed branch {}", branch);
         Ok(())
     }
 
     async fn remove_current_branch(&mut self) -> Result<()> {
+        if self.current_stack().is_none() {
+            bail!("Not on a stack branch");
+        }
         let current = self.repo.current_branch()?;
-        self.remove_branch_from_stack(&current.to_string())?;
         let delete_branch = Confirm::new()
             .with_prompt("Delete local branch?")
             .interact()
             .unwrap();
 
-        if delete_branch {
+        self.run_operation(Operation::Remove {
+            branch: current.to_string(),
+            delete_branch,
+        })
+        .await
+    }
+
+    async fn remove_branch(&mut self, branch: &String, delete_branch: bool) -> Result<()> {
+        if !self.is_step_completed(&Step::RemovedFromStack(branch.clone())) {
+            self.remove_branch_from_stack(branch)?;
+            self.complete_step(Step::RemovedFromStack(branch.clone()))?;
+        }
+
+        if delete_branch && !self.is_step_completed(&Step::DeletedBranch(branch.clone())) {
+            self.repo.cmd(["branch", "-d", branch.as_str()])?;
+            println!("Deleted branch {}", branch);
+            self.complete_step(Step::DeletedBranch(branch.clone()))?;
+        }
+
+        // The stack is gone if its last branch was removed
+        if self.current_stack().is_some() {
+            self.sync(true).await?;
+        }
+        Ok(())
+    }
+
+    /// Starts a journaled operation that can be resumed with `gs continue` or
+    /// rolled back with `gs abort` if it gets interrupted
+    async fn run_operation(&mut self, operation: Operation) -> Result<()> {
+        if let Some(journal) = &self.journal {
+            bail!(
+                "Another operation ({}) is in progress. Run {} to resume it or {} to roll it back.",
+                journal.operation,
+                style("gs continue").green(),
+                style("gs abort").green()
+            );
+        }
+        let backup = backup::create(&self.repo, &operation.to_string(), &self.state)?;
+        let journal = Journal {
+            operation,
+            original_branch: self.repo.current_branch()?.to_string(),
+            backup,
+            completed: vec![],
+        };
+        journal.write(&self.gstack_dir)?;
+        self.journal = Some(journal);
+        self.resume_operation().await
+    }
+
+    async fn resume_operation(&mut self) -> Result<()> {
+        let operation = self.journal.as_ref().unwrap().operation.clone();
+        let result = match &operation {
+            Operation::Sync {
+                update_descriptions,
+            } => self.sync(*update_descriptions).await,
+            Operation::Restack { from } => self.restack(from),
+            Operation::Reorder { branches } => self.reorder(branches).await,
+            Operation::Fold {
+                branch,
+                into,
+                folded_pr,
+                surviving_pr,
+            } => self.fold(branch, into, *folded_pr, *surviving_pr).await,
+            Operation::Merge { strategy } => self.merge_pull_requests(*strategy).await,
+            Operation::Remove {
+                branch,
+                delete_branch,
+            } => self.remove_branch(branch, *delete_branch).await,
+        };
+        if let Err(err) = &result {
+            if let Some(conflict) = err.downcast_ref::<RebaseConflict>() {
+                let location = match conflict.worktree == self.repo.toplevel()? {
+                    true => String::new(),
+                    false => format!(" in {}", conflict.worktree.display()),
+                };
+                println!(
+                    "{}\nResolve the conflicts{}, stage them with {} and run {} to finish the rebase and restack the remaining branches, or {} to restore all branches.",
+                    err,
+                    location,
+                    style("git add").green(),
+                    style("gs continue").green(),
+                    style("gs abort").green()
+                );
+            } else {
+                println!(
+                    "Operation interrupted. Fix the problem and run {} to resume it or {} to roll it back.",
+                    style("gs continue").green(),
+                    style("gs abort").green()
+                );
+            }
+            return result;
+        }
+        Journal::delete(&self.gstack_dir)?;
+        self.journal = None;
+        Ok(())
+    }
+
+    async fn continue_operation(&mut self) -> Result<()> {
+        let Some(journal) = &self.journal else {
+            println!("No operation in progress");
+            return Ok(());
+        };
+        println!("Resuming {}...", journal.operation);
+        let original_branch = journal.original_branch.clone();
+        if let Some(repo) = self.rebasing_worktree()? {
+            if let Err(err) = repo.continue_rebase() {
+                if err.downcast_ref::<RebaseConflict>().is_some() {
+                    println!(
+                        "{}\nResolve the conflicts, stage them with {} and run {} again.",
+                        err,
+                        style("git add").green(),
+                        style("gs continue").green()
+                    );
+                }
+                return Err(err);
+            }
+        }
+        if self.current_stack().is_none() {
             self.repo
-                .cmd(&["branch", "-d", current.to_string().as_str()])?;
-            println!("Deleted branch {}", current.to_string());
+                .switch_branch(&BranchName::from_str(&original_branch)?)?;
         }
+        self.resume_operation().await?;
+        // The operation picks up from the branch the conflict happened on, return to where it
+        // was started unless the branch was merged, folded or removed in the meantime
+        let in_stack = self
+            .state
+            .stacks
+            .iter()
+            .any(|stack| stack.branches.contains(&original_branch));
+        if in_stack
+            && self.repo.current_branch()?.to_string() != original_branch
+            && self.repo.other_worktree(&original_branch)?.is_none()
+        {
+            self.repo
+                .switch_branch(&BranchName::from_str(&original_branch)?)?;
+        }
+        Ok(())
+    }
 
-        self.sync(true).await?;
+    /// Repository of the worktree a stack branch is currently being rebased in, which is not
+    /// necessarily the current one
+    fn rebasing_worktree(&self) -> Result<Option<Repository>> {
+        if self.repo.is_rebase_in_progress()? {
+            return Ok(Some(Repository::new(self.repo.toplevel()?)));
+        }
+        for path in self.repo.worktree_paths()? {
+            let repo = Repository::new(path);
+            if repo.is_rebase_in_progress()? {
+                return Ok(Some(repo));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Restores all local branches and the state to how they were before the interrupted operation.
+    /// Changes that were already pushed or made on GitHub are not reverted.
+    fn abort_operation(&mut self) -> Result<()> {
+        let Some(journal) = self.journal.take() else {
+            println!("No operation in progress");
+            return Ok(());
+        };
+        if let Some(repo) = self.rebasing_worktree()? {
+            repo.abort_rebase()?;
+        }
+        let backup = backup::read(&self.repo, &journal.backup)?;
+        backup::restore_branches(&self.repo, &backup)?;
+        self.state = backup.state;
+        self.state.write(self.gstack_dir.clone())?;
+        if self
+            .repo
+            .other_worktree(&journal.original_branch)?
+            .is_none()
+        {
+            self.repo
+                .switch_branch(&BranchName::from_str(&journal.original_branch)?)?;
+        }
+        Journal::delete(&self.gstack_dir)?;
+        println!(
+            "Aborted {} and restored {} branches. Changes already pushed to the remote or made on github were not reverted.",
+            journal.operation,
+            backup.branch_heads.len()
+        );
+        Ok(())
+    }
+
+    fn is_step_completed(&self, step: &Step) -> bool {
+        self.journal
+            .as_ref()
+            .is_some_and(|journal| journal.is_completed(step))
+    }
+
+    /// Records a completed step of the running operation, if any
+    fn complete_step(&mut self, step: Step) -> Result<()> {
+        if let Some(journal) = &mut self.journal {
+            journal.completed.push(step);
+            journal.write(&self.gstack_dir)?;
+        }
+        Ok(())
+    }
+
+    /// Forgets the rebase and push steps of a finished sync, so that subsequent syncs
+    /// of the same operation run again
+    fn clear_sync_steps(&mut self) -> Result<()> {
+        if let Some(journal) = &mut self.journal {
+            journal.completed.retain(|step| {
+                !matches!(
+                    step,
+                    Step::Pulled
+                        | Step::Rebased(_)
+                        | Step::Pushed(_)
+                        | Step::DetectedMerged(_)
+                        | Step::DeletedRemoteBranch(_)
+                )
+            });
+            journal.write(&self.gstack_dir)?;
+        }
+        Ok(())
+    }
+
+    fn undo(&mut self, id: &Option<String>, list: bool) -> Result<()> {
+        if list {
+            for backup in backup::list(&self.repo)? {
+                println!(
+                    "{}: before {} ({}, {} branches)",
+                    style(&backup.id).yellow(),
+                    style(&backup.command).cyan(),
+                    backup.age(),
+                    backup.branch_heads.len()
+                );
+            }
+            return Ok(());
+        }
+        if let Some(journal) = &self.journal {
+            bail!(
+                "Another operation ({}) is in progress. Run {} to roll it back instead.",
+                journal.operation,
+                style("gs abort").green()
+            );
+        }
+
+        let backup = match id {
+            Some(id) => backup::read(&self.repo, id)?,
+            None => match backup::list(&self.repo)?.into_iter().next() {
+                Some(backup) => backup,
+                None => bail!("No backups found"),
+            },
+        };
+        let restore = Confirm::new()
+            .with_prompt(format!(
+                "Restore {} branches and the state from before {} ({})?",
+                backup.branch_heads.len(),
+                backup.command,
+                backup.age()
+            ))
+            .interact()
+            .unwrap();
+        if !restore {
+            return Ok(());
+        }
+
+        backup::restore_branches(&self.repo, &backup)?;
+        self.state = backup.state;
+        self.state.write(self.gstack_dir.clone())?;
+        println!(
+            "Restored {} branches and the state from backup {}",
+            backup.branch_heads.len(),
+            style(&backup.id).yellow()
+        );
+        Ok(())
+    }
+
+    /// Reports every inconsistency between the stacks and the repository or github and
+    /// optionally repairs them, backing up the state and branches first
+    async fn doctor(&mut self, fix: bool) -> Result<()> {
+        if self.repo.remote_repo_url().is_ok() {
+            if let Err(err) = self.repo.cmd(["fetch", "--prune", "origin"]) {
+                println!(
+                    "Could not fetch origin, remote branches might be outdated: {}",
+                    err
+                );
+            }
+        }
+        let mut problems = doctor::check_branches(&self.repo, &self.state);
+        let github = match (&self.github, self.repo.remote_repo_info()) {
+            (Some(github), Ok(remote)) => {
+                let open_pulls = self.get_open_pull_requests().await?;
+                problems.extend(
+                    doctor::check_pull_requests(github, &remote, &open_pulls, &self.state).await?,
+                );
+                Some((github.clone(), remote))
+            }
+            _ => {
+                println!("No github token or remote configured, skipping PR checks");
+                None
+            }
+        };
+        if let Some(journal) = &self.journal {
+            problems.push(doctor::Problem::UnfinishedOperation {
+                operation: journal.operation.to_string(),
+            });
+        }
+        if problems.is_empty() {
+            println!("No problems found");
+            return Ok(());
+        }
+        for problem in &problems {
+            println!("{} {}", style("✗").red(), problem.describe(&self.state));
+        }
+        if !fix {
+            println!(
+                "Found {} problems, run {} to repair them",
+                problems.len(),
+                style("gs doctor --fix").green()
+            );
+            return Ok(());
+        }
+
+        backup::create(&self.repo, "doctor", &self.state)?;
+        let mut unfixed = 0;
+        for problem in &problems {
+            if let doctor::Problem::WrongPullRequestBase { stack, branch, .. } = problem {
+                // The new base of the PR has to exist on the remote
+                let stack = &self.state.stacks[*stack];
+                let parent = stack
+                    .parent(branch)
+                    .filter(|parent| **parent != stack.base_branch)
+                    .cloned();
+                if let (Some(parent), Some(_)) = (parent, &github) {
+                    self.push_branches(&[parent])?;
+                }
+            }
+            let github = github
+                .as_ref()
+                .map(|(github, remote)| (github.as_ref(), remote));
+            if !doctor::fix(problem, &self.repo, &mut self.state, github).await? {
+                unfixed += 1;
+            }
+        }
+        self.state.stacks.retain(|stack| !stack.branches.is_empty());
+        self.state.write(self.gstack_dir.clone())?;
+        println!("Fixed {} problems", problems.len() - unfixed);
+        if unfixed > 0 {
+            println!("{} problems have to be fixed manually", unfixed);
+        }
         Ok(())
     }
 
     fn reset(&mut self) -> Result<()> {
+        backup::create(&self.repo, "reset", &self.state)?;
         let mut deleted = 0;
         self.state
             .stacks
@@ -589,11 +2362,11 @@ impl GsContext {
             .flat_map(|stack| stack.branches.clone())
             .for_each(|branch| {
                 deleted += 1;
-                self.repo.cmd(&["branch", "-d", branch.as_str()]);
+                let _ = self.repo.cmd(["branch", "-d", branch.as_str()]);
             });
         println!("Deleted {} branches.", deleted);
         self.state = GsState::default();
-        self.state.write(self.base_path.clone())?;
+        self.state.write(self.gstack_dir.clone())?;
         println!("Deleted all stacks and reset state.");
         Ok(())
     }
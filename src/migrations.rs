@@ -0,0 +1,232 @@
+use std::collections::BTreeMap;
+
+use anyhow::{bail, Result};
+use ron::Value;
+use serde::{Deserialize, Serialize};
+
+use crate::state::{GitStack, GsState, StackPullRequest};
+
+/// Version of the state format written by this version of gstack
+pub const CURRENT_VERSION: u32 = 3;
+
+/// The migration at index `n` upgrades a state of version `n` to version `n + 1`
+const MIGRATIONS: [fn(Value) -> Result<Value>; CURRENT_VERSION as usize] =
+    [v0_to_v1, v1_to_v2, v2_to_v3];
+
+/// Upgrades a state of this or an older version of gstack to the current format.
+/// Returns the upgraded state together with the version it was written with.
+pub fn parse_state(value: Value) -> Result<(GsState, u32)> {
+    let version = version(&value)?;
+    if version > CURRENT_VERSION {
+        bail!(
+            "The state was written by a newer version of gstack (state version {}, this version supports up to {}). Please update gstack.",
+            version,
+            CURRENT_VERSION
+        );
+    }
+    let value = MIGRATIONS[version as usize..]
+        .iter()
+        .try_fold(value, |value, migrate| migrate(value))?;
+    Ok((value.into_rust()?, version))
+}
+
+/// Parses a stack shared through git refs, which is stored as a state with a single stack.
+/// Stacks shared before the state had a version are stored without the surrounding state.
+pub fn parse_shared_stack(value: Value) -> Result<GitStack> {
+    let value = match field(&value, "stacks") {
+        Some(_) => value,
+        None => to_value(&BTreeMap::from([("stacks", vec![value])]))?,
+    };
+    let (state, _) = parse_state(value)?;
+    match state.stacks.into_iter().next() {
+        Some(stack) => Ok(stack),
+        None => bail!("The shared stack is empty"),
+    }
+}
+
+/// States written before the version was recorded have version 0
+fn version(value: &Value) -> Result<u32> {
+    match field(value, "version") {
+        Some(version) => Ok(version.clone().into_rust()?),
+        None => Ok(0),
+    }
+}
+
+fn field<'a>(value: &'a Value, name: &str) -> Option<&'a Value> {
+    match value {
+        Value::Map(map) => map.get(&Value::String(name.to_string())),
+        _ => None,
+    }
+}
+
+fn to_value<T: Serialize>(value: &T) -> Result<Value> {
+    Ok(ron::from_str(&ron::to_string(value)?)?)
+}
+
+/// Stacks before the version was recorded, fields were added over time
+#[derive(Deserialize)]
+struct GitStackV0 {
+    prefix: Option<String>,
+    base_branch: String,
+    branches: Vec<String>,
+    #[serde(default)]
+    parents: BTreeMap<String, String>,
+    #[serde(default)]
+    pull_requests: BTreeMap<String, StackPullRequest>,
+    #[serde(default)]
+    fork_points: BTreeMap<String, String>,
+}
+
+#[derive(Deserialize)]
+struct GsStateV0 {
+    stacks: Vec<GitStackV0>,
+}
+
+/// Stacks before they had an id
+#[derive(Serialize, Deserialize)]
+struct GitStackV1 {
+    prefix: Option<String>,
+    base_branch: String,
+    branches: Vec<String>,
+    parents: BTreeMap<String, String>,
+    pull_requests: BTreeMap<String, StackPullRequest>,
+    fork_points: BTreeMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct GsStateV1 {
+    version: u32,
+    stacks: Vec<GitStackV1>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct GsStateV2 {
+    version: u32,
+    stacks: Vec<GitStackV1>,
+    remote_heads: BTreeMap<String, String>,
+}
+
+/// Adds the version and records the parents of stacks that were written before stacks could be
+/// trees, these are linear
+fn v0_to_v1(value: Value) -> Result<Value> {
+    let state: GsStateV0 = value.into_rust()?;
+    let stacks = state
+        .stacks
+        .into_iter()
+        .map(|stack| {
+            let mut stack = GitStack {
+                prefix: stack.prefix,
+                base_branch: stack.base_branch,
+                branches: stack.branches,
+                parents: stack.parents,
+                pull_requests: stack.pull_requests,
+                fork_points: stack.fork_points,
+                ..Default::default()
+            };
+            stack.record_linear_parents();
+            GitStackV1 {
+                prefix: stack.prefix,
+                base_branch: stack.base_branch,
+                branches: stack.branches,
+                parents: stack.parents,
+                pull_requests: stack.pull_requests,
+                fork_points: stack.fork_points,
+            }
+        })
+        .collect();
+    to_value(&GsStateV1 { version: 1, stacks })
+}
+
+/// Adds the remote heads, which are recorded by the next sync
+fn v1_to_v2(value: Value) -> Result<Value> {
+    let state: GsStateV1 = value.into_rust()?;
+    to_value(&GsStateV2 {
+        version: 2,
+        stacks: state.stacks,
+        remote_heads: BTreeMap::new(),
+    })
+}
+
+/// Adds the id of each stack. The current first branch is used, so machines sharing a stack
+/// assign it the same id.
+fn v2_to_v3(value: Value) -> Result<Value> {
+    let state: GsStateV2 = value.into_rust()?;
+    let stacks = state
+        .stacks
+        .into_iter()
+        .map(|stack| GitStack {
+            id: stack.branches.first().cloned().unwrap_or_default(),
+            prefix: stack.prefix,
+            base_branch: stack.base_branch,
+            branches: stack.branches,
+            parents: stack.parents,
+            pull_requests: stack.pull_requests,
+            fork_points: stack.fork_points,
+        })
+        .collect();
+    to_value(&GsState {
+        version: 3,
+        stacks,
+        remote_heads: state.remote_heads,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(contents: &str) -> Result<(GsState, u32)> {
+        parse_state(ron::from_str(contents)?)
+    }
+
+    #[test]
+    fn upgrades_v0_state() {
+        let (state, version) = parse(
+            r#"(stacks: [(prefix: Some("x"), base_branch: "main", branches: ["x/a", "x/b"])])"#,
+        )
+        .unwrap();
+        assert_eq!(version, 0);
+        assert_eq!(state.version, CURRENT_VERSION);
+        assert!(state.remote_heads.is_empty());
+        let stack = &state.stacks[0];
+        assert_eq!(stack.id, "x/a");
+        assert_eq!(
+            stack.parents,
+            BTreeMap::from([
+                ("x/a".to_string(), "main".to_string()),
+                ("x/b".to_string(), "x/a".to_string()),
+            ])
+        );
+        assert!(stack.pull_requests.is_empty());
+    }
+
+    #[test]
+    fn upgrades_v1_state() {
+        let (state, version) = parse(
+            r#"(
+                version: 1,
+                stacks: [(
+                    prefix: None,
+                    base_branch: "main",
+                    branches: ["a", "b"],
+                    parents: {"a": "main", "b": "main"},
+                    pull_requests: {"a": (number: 1, url: "url", head_sha: "sha")},
+                    fork_points: {},
+                )],
+            )"#,
+        )
+        .unwrap();
+        assert_eq!(version, 1);
+        assert!(state.remote_heads.is_empty());
+        let stack = &state.stacks[0];
+        assert_eq!(stack.id, "a");
+        assert_eq!(stack.parent("b"), Some(&"main".to_string()));
+        assert_eq!(stack.pull_requests["a"].number, 1);
+    }
+
+    #[test]
+    fn rejects_newer_state() {
+        let err = parse("(version: 99, stacks: [], remote_heads: {})").unwrap_err();
+        assert!(err.to_string().contains("newer version of gstack"));
+    }
+}
@@ -0,0 +1,235 @@
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Result};
+use console::style;
+use indicatif::ProgressBar;
+use octocrab::{
+    models::{
+        pulls::{MergeableState, PullRequest},
+        CombinedStatus, StatusState,
+    },
+    params::repos::Commitish,
+    Octocrab,
+};
+use tokio::time::sleep;
+
+use crate::repo_extensions::RemoteRepoInfo;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+enum ChecksStatus {
+    Passed,
+    Pending(Vec<String>),
+    Failed(Vec<String>),
+}
+
+/// The open PR with `branch` of the repository itself as its head. PR's opened from forks
+/// can have head branches that are named like branches of the repository, e.g. `main`.
+pub fn pull_request_of<'a>(open_pulls: &'a [PullRequest], branch: &str) -> Option<&'a PullRequest> {
+    open_pulls
+        .iter()
+        .find(|pr| pr.head.ref_field == branch && !is_from_fork(pr))
+}
+
+/// The open PR opened from a fork with `branch` as its head, unless the head is named like
+/// the base branch
+pub fn fork_pull_request_of<'a>(
+    open_pulls: &'a [PullRequest],
+    branch: &str,
+) -> Option<&'a PullRequest> {
+    open_pulls
+        .iter()
+        .find(|pr| pr.head.ref_field == branch && pr.base.ref_field != branch && is_from_fork(pr))
+}
+
+/// PR's of deleted forks have no head repository
+pub fn is_from_fork(pr: &PullRequest) -> bool {
+    pr.head.repo.as_ref().map(|repo| repo.id) != pr.base.repo.as_ref().map(|repo| repo.id)
+}
+
+/// Polls a PR until GitHub reports it as mergeable and all of its commit statuses and
+/// check runs have passed. Fails as soon as a check fails or the PR can not be merged.
+/// If `head` is given, waits until GitHub sees it as the head of the PR, so the checks of a
+/// head that was just replaced by a push don't count.
+pub async fn wait_until_mergeable(
+    github: &Octocrab,
+    remote: &RemoteRepoInfo,
+    number: u64,
+    head: Option<&str>,
+    timeout: Duration,
+) -> Result<()> {
+    let spinner = ProgressBar::new_spinner();
+    spinner.enable_steady_tick(Duration::from_millis(100));
+    spinner.set_message(format!("Waiting for PR #{} to become mergeable...", number));
+    let result = poll_until_mergeable(github, remote, number, head, timeout, &spinner).await;
+    spinner.finish_and_clear();
+    result
+}
+
+async fn poll_until_mergeable(
+    github: &Octocrab,
+    remote: &RemoteRepoInfo,
+    number: u64,
+    head: Option<&str>,
+    timeout: Duration,
+    spinner: &ProgressBar,
+) -> Result<()> {
+    let pulls = github.pulls(&remote.owner, &remote.name);
+    let deadline = Instant::now() + timeout;
+    loop {
+        let pr = pulls.get(number).await?;
+        if head.is_some_and(|head| pr.head.sha != head) {
+            spinner.set_message(format!(
+                "Waiting for GitHub to pick up the pushed head of PR #{}...",
+                number
+            ));
+            if Instant::now() >= deadline {
+                bail!(
+                    "Timed out after {}s waiting for GitHub to pick up the pushed head of PR #{}",
+                    timeout.as_secs(),
+                    number
+                );
+            }
+            sleep(POLL_INTERVAL).await;
+            continue;
+        }
+        let checks = checks_status(github, remote, &pr.head.sha).await?;
+        if let ChecksStatus::Failed(failed) = &checks {
+            bail!(
+                "Checks failed for PR #{}: {}. Fix them and run the merge again.",
+                number,
+                failed.join(", ")
+            );
+        }
+        match pr.mergeable_state {
+            Some(MergeableState::Dirty) => {
+                bail!("PR #{} has merge conflicts with its base branch", number)
+            }
+            Some(MergeableState::Draft) => {
+                bail!(
+                    "PR #{} is a draft, mark it as ready for review first",
+                    number
+                )
+            }
+            _ => {}
+        }
+        if pr.mergeable == Some(false) {
+            bail!("GitHub reports PR #{} as not mergeable", number);
+        }
+
+        let ready = matches!(
+            pr.mergeable_state,
+            Some(MergeableState::Clean) | Some(MergeableState::HasHooks)
+        );
+        match checks {
+            ChecksStatus::Passed if ready && pr.mergeable == Some(true) => return Ok(()),
+            ChecksStatus::Pending(pending) => spinner.set_message(format!(
+                "Waiting for checks of PR #{}: {}",
+                number,
+                pending.join(", ")
+            )),
+            _ => spinner.set_message(format!(
+                "Waiting for PR #{} to become mergeable (state: {})...",
+                number,
+                mergeable_state_name(&pr.mergeable_state)
+            )),
+        }
+
+        if Instant::now() >= deadline {
+            bail!(
+                "Timed out after {}s waiting for PR #{} to become mergeable (state: {})",
+                timeout.as_secs(),
+                number,
+                mergeable_state_name(&pr.mergeable_state)
+            );
+        }
+        sleep(POLL_INTERVAL).await;
+    }
+}
+
+/// Polls a PR until GitHub reports it as merged
+pub async fn wait_until_merged(
+    github: &Octocrab,
+    remote: &RemoteRepoInfo,
+    number: u64,
+    timeout: Duration,
+) -> Result<()> {
+    let pulls = github.pulls(&remote.owner, &remote.name);
+    let spinner = ProgressBar::new_spinner();
+    spinner.enable_steady_tick(Duration::from_millis(100));
+    spinner.set_message(format!("Waiting for PR #{} to be merged...", number));
+    let deadline = Instant::now() + timeout;
+    loop {
+        let pr = pulls.get(number).await?;
+        if pr.merged_at.is_some() {
+            spinner.finish_and_clear();
+            println!("Merged PR #{}", style(number).green());
+            return Ok(());
+        }
+        if Instant::now() >= deadline {
+            spinner.finish_and_clear();
+            bail!(
+                "Timed out after {}s waiting for PR #{} to be merged",
+                timeout.as_secs(),
+                number
+            );
+        }
+        sleep(POLL_INTERVAL).await;
+    }
+}
+
+async fn checks_status(
+    github: &Octocrab,
+    remote: &RemoteRepoInfo,
+    sha: &str,
+) -> Result<ChecksStatus> {
+    let mut pending = vec![];
+    let mut failed = vec![];
+
+    let combined: CombinedStatus = github
+        .get(
+            format!(
+                "/repos/{}/{}/commits/{}/status",
+                remote.owner, remote.name, sha
+            ),
+            None::<&()>,
+        )
+        .await?;
+    for status in combined.statuses {
+        let name = status.context.unwrap_or("status".to_string());
+        match status.state {
+            StatusState::Success => {}
+            StatusState::Pending => pending.push(name),
+            _ => failed.push(name),
+        }
+    }
+
+    let check_runs = github
+        .checks(&remote.owner, &remote.name)
+        .list_check_runs_for_git_ref(Commitish(sha.to_string()))
+        .per_page(100)
+        .send()
+        .await?;
+    for run in check_runs.check_runs {
+        match run.conclusion.as_deref() {
+            None => pending.push(run.name),
+            Some("success") | Some("neutral") | Some("skipped") => {}
+            Some(_) => failed.push(run.name),
+        }
+    }
+
+    Ok(if !failed.is_empty() {
+        ChecksStatus::Failed(failed)
+    } else if !pending.is_empty() {
+        ChecksStatus::Pending(pending)
+    } else {
+        ChecksStatus::Passed
+    })
+}
+
+fn mergeable_state_name(state: &Option<MergeableState>) -> String {
+    state
+        .as_ref()
+        .map(|state| format!("{:?}", state).to_lowercase())
+        .unwrap_or("unknown".to_string())
+}
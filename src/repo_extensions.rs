@@ -2,59 +2,131 @@ use anyhow::bail;
 use anyhow::Result;
 use console::style;
 use regex::Regex;
-use std::str::FromStr;
+use std::{
+    collections::BTreeMap,
+    fmt, fs,
+    io::Write,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    str::FromStr,
+};
 
-use rustygit::{types::BranchName, Repository};
+use rustygit::{error::GitError, types::BranchName, Repository};
 
 pub struct RemoteRepoInfo {
     pub owner: String,
     pub name: String,
 }
 
+/// A rebase that stopped because of conflicts and is waiting for the user to resolve them
+#[derive(Debug)]
+pub struct RebaseConflict {
+    pub branch: String,
+    pub files: Vec<String>,
+    /// Top level of the worktree the rebase is running in
+    pub worktree: PathBuf,
+}
+
+impl fmt::Display for RebaseConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Rebasing stack branch {} stopped with conflicts in:\n  {}",
+            self.branch,
+            self.files.join("\n  ")
+        )
+    }
+}
+
+impl std::error::Error for RebaseConflict {}
+
 pub trait RepoExtenstions {
     fn current_branch(&self) -> Result<BranchName>;
-    fn rebase(&self, branch: BranchName, on: BranchName) -> Result<()>;
-    fn pull_all(&self, branches: &Vec<String>) -> Result<()>;
+    fn rebase_onto(&self, branch: BranchName, onto: BranchName, upstream: &str) -> Result<()>;
+    fn fast_forward_from_remote(&self, remote: &str, branch: &str) -> Result<()>;
+    fn is_rebase_in_progress(&self) -> Result<bool>;
+    fn continue_rebase(&self) -> Result<()>;
+    fn abort_rebase(&self) -> Result<()>;
     fn remote_repo_url(&self) -> Result<String>;
     fn remote_repo_info(&self) -> Result<RemoteRepoInfo>;
-    fn force_push_to_upstream(&self, upstream: &str, upstream_branch: &BranchName) -> Result<()>;
-    fn head_sha(&self, branch_name: &String) -> Result<String>;
+    fn head_sha(&self, branch_name: &str) -> Result<String>;
+    fn has_remote_branch(&self, remote: &str, branch: &str) -> bool;
+    fn merge_base(&self, a: &str, b: &str) -> Result<String>;
+    fn is_ancestor(&self, ancestor: &str, descendant: &str) -> bool;
+    fn is_merged_into(&self, branch: &str, upstream: &str, base: &str) -> Result<bool>;
+    fn write_blob(&self, contents: &str) -> Result<String>;
+    fn cmd_with_input(&self, args: &[&str], input: &str) -> Result<String>;
+    fn run_editor(&self, path: &Path) -> Result<()>;
+    fn read_blob(&self, sha: &str) -> Result<String>;
+    fn toplevel(&self) -> Result<PathBuf>;
+    fn worktree_paths(&self) -> Result<Vec<PathBuf>>;
+    fn worktrees(&self) -> Result<BTreeMap<String, PathBuf>>;
+    fn other_worktree(&self, branch: &str) -> Result<Option<PathBuf>>;
+    fn add_worktree(&self, branch: &str, path: &Path) -> Result<()>;
 }
 
 impl RepoExtenstions for Repository {
     fn current_branch(&self) -> Result<BranchName> {
-        let branches = self.cmd_out(&["rev-parse", "--abbrev-ref", "HEAD"])?;
+        let branches = self.cmd_out(["rev-parse", "--abbrev-ref", "HEAD"])?;
         Ok(BranchName::from_str(branches.first().unwrap())?)
     }
 
-    fn rebase(&self, branch: BranchName, on: BranchName) -> Result<()> {
+    ///Rebase only the commits of `branch` that are not reachable from `upstream` onto `onto`
+    fn rebase_onto(&self, branch: BranchName, onto: BranchName, upstream: &str) -> Result<()> {
         self.switch_branch(&branch)?;
-        let output = self.cmd_out(&["rebase", "--update-refs", on.to_string().as_str()])?;
+        let output = self
+            .cmd_out(["rebase", "--onto", onto.to_string().as_str(), upstream])
+            .map_err(|err| rebase_error(self, &branch.to_string(), err))?;
         println!(
-            "Rebased branch {} on {} with output: {:?}",
+            "Rebased branch {} onto {} with output: {:?}",
             style(branch).green(),
-            style(on).green(),
+            style(onto).green(),
             style(output.join(",")).white().on_black()
         );
         Ok(())
     }
 
-    fn pull_all(&self, branches: &Vec<String>) -> Result<()> {
-        for branch in branches {
-            self.switch_branch(&BranchName::from_str(branch.as_str())?)?;
-            let output = self.cmd_out(&["pull", "--rebase"])?;
-            println!(
-                "Pulled branch {} with output: {:?}",
-                style(branch).green(),
-                style(output.join(",")).white().on_black()
-            );
+    ///Fast forward a local branch that is not checked out to the state of its remote counterpart
+    fn fast_forward_from_remote(&self, remote: &str, branch: &str) -> Result<()> {
+        self.cmd(["fetch", remote, format!("{}:{}", branch, branch).as_str()])?;
+        Ok(())
+    }
+
+    fn is_rebase_in_progress(&self) -> Result<bool> {
+        for dir in ["rebase-merge", "rebase-apply"] {
+            let path = self.cmd_out(["rev-parse", "--path-format=absolute", "--git-path", dir])?;
+            if PathBuf::from(path.join("")).exists() {
+                return Ok(true);
+            }
         }
+        Ok(false)
+    }
+
+    ///Continue a stopped rebase after the user resolved and staged all conflicts
+    fn continue_rebase(&self) -> Result<()> {
+        let head_name = self.cmd_out([
+            "rev-parse",
+            "--path-format=absolute",
+            "--git-path",
+            "rebase-merge/head-name",
+        ])?;
+        let branch = fs::read_to_string(head_name.join(""))
+            .map(|name| name.trim().trim_start_matches("refs/heads/").to_string())
+            .unwrap_or_default();
+        // Keep the original commit messages instead of opening an editor
+        self.cmd_out(["-c", "core.editor=true", "rebase", "--continue"])
+            .map_err(|err| rebase_error(self, &branch, err))?;
+        Ok(())
+    }
+
+    fn abort_rebase(&self) -> Result<()> {
+        self.cmd(["rebase", "--abort"])?;
         Ok(())
     }
 
     fn remote_repo_url(&self) -> Result<String> {
-        let output = self.cmd_out(&["config", "--get", "remote.origin.url"])?;
-        if (output.is_empty()) {
+        let output = self.cmd_out(["config", "--get", "remote.origin.url"])?;
+        if output.is_empty() {
             bail!("No remote found");
         }
         Ok(output.first().unwrap().clone())
@@ -74,73 +146,191 @@ impl RepoExtenstions for Repository {
         }
     }
 
-    ///Force push the current branch to its associated remote, specifying the upstream branch,
-    ///but only if there are changes to push
-    fn force_push_to_upstream(&self, upstream: &str, upstream_branch: &BranchName) -> Result<()> {
-        // Check if there are differences between local and remote branch
-        let remote_ref = format!("{}/{}", upstream, upstream_branch);
-
-        // Get the commit hash of the local HEAD
-        let local_commit = self.cmd_out(&["rev-parse", "HEAD"])?;
-        let local_commit = local_commit.join("").trim().to_string();
-
-        // Try to get the commit hash of the remote branch
-        let remote_commit_result = self.cmd_out(&["rev-parse", &remote_ref]);
-
-        // Determine if we need to push
-        let need_to_push = match remote_commit_result {
-            // Remote branch exists, check if it differs from local
-            Ok(remote_commit) => {
-                let remote_commit = remote_commit.join("").trim().to_string();
-
-                // Check if local and remote commits are different
-                if local_commit != remote_commit {
-                    // Check if local is ahead or has diverged from remote
-                    let base_commit = self.cmd_out(&["merge-base", "HEAD", &remote_ref])?;
-                    let base_commit = base_commit.join("").trim().to_string();
-
-                    // If different and valid ancestry, we should push
-                    true
-                } else {
-                    // Commits are identical, no need to push
-                    println!(
-                        "No changes to push for branch {}: local and remote are at the same commit",
-                        style(upstream_branch).green()
-                    );
-                    false
-                }
-            }
-            // Remote branch doesn't exist, we should push
-            Err(_) => {
-                println!(
-                    "Remote branch {} doesn't exist yet - will push",
-                    style(&remote_ref).yellow()
-                );
-                true
-            }
+    fn head_sha(&self, branch_name: &str) -> Result<String> {
+        let output = self.cmd_out(["rev-parse", branch_name])?;
+        Ok(output.first().unwrap().clone())
+    }
+
+    fn has_remote_branch(&self, remote: &str, branch: &str) -> bool {
+        let remote_ref = format!("refs/remotes/{}/{}", remote, branch);
+        self.cmd(["rev-parse", "--verify", "--quiet", remote_ref.as_str()])
+            .is_ok()
+    }
+
+    fn merge_base(&self, a: &str, b: &str) -> Result<String> {
+        let output = self.cmd_out(["merge-base", a, b])?;
+        match output.first() {
+            Some(sha) => Ok(sha.clone()),
+            None => bail!("{} and {} have no common ancestor", a, b),
+        }
+    }
+
+    ///Whether `ancestor` is reachable from `descendant`
+    fn is_ancestor(&self, ancestor: &str, descendant: &str) -> bool {
+        self.cmd(["merge-base", "--is-ancestor", ancestor, descendant])
+            .is_ok()
+    }
+
+    ///Whether the commits `branch` made on top of `upstream` are part of `base`, also if they
+    ///were rebased or squashed into a single commit when merging
+    fn is_merged_into(&self, branch: &str, upstream: &str, base: &str) -> Result<bool> {
+        let range = format!("{}..{}", upstream, branch);
+        let own_commits = self
+            .cmd_out(["rev-list", "--count", range.as_str()])?
+            .join("");
+        // A branch without commits of its own is trivially part of every descendant
+        if own_commits == "0" {
+            return Ok(false);
+        }
+        if self.is_ancestor(branch, base) {
+            return Ok(true);
+        }
+        // Commits with an equivalent patch in `base` are prefixed with `-`
+        let cherry = self.cmd_out(["cherry", base, branch, upstream])?;
+        if cherry.iter().all(|line| line.starts_with('-')) {
+            return Ok(true);
+        }
+        // Squash merges show up as a single commit with the combined changes of the branch
+        let diff = self.cmd_out(["diff", upstream, branch])?.join("\n") + "\n";
+        let squashed = self.cmd_with_input(&["patch-id", "--stable"], &diff)?;
+        let Some(squashed) = squashed.split_whitespace().next() else {
+            return Ok(false);
         };
+        let range = format!("{}..{}", upstream, base);
+        let log = self.cmd_out(["log", "-p", range.as_str()])?.join("\n") + "\n";
+        let patch_ids = self.cmd_with_input(&["patch-id", "--stable"], &log)?;
+        Ok(patch_ids
+            .lines()
+            .any(|line| line.split_whitespace().next() == Some(squashed)))
+    }
+
+    ///Store `contents` in the object database, returning the sha of the new blob
+    fn write_blob(&self, contents: &str) -> Result<String> {
+        self.cmd_with_input(&["hash-object", "-w", "--stdin"], contents)
+    }
 
-        // Only push if needed
-        if need_to_push {
-            let output = self.cmd_out(&[
-                "push",
-                "-u",
-                upstream,
-                upstream_branch.to_string().as_str(),
-                "--force-with-lease",
-            ])?;
-            println!(
-                "Force pushed to upstream branch {} with output: {:?}",
-                style(upstream_branch).green(),
-                style(output.join(",")).white().on_black()
-            );
+    ///Run a git command with `input` as its stdin, returning its trimmed output
+    fn cmd_with_input(&self, args: &[&str], input: &str) -> Result<String> {
+        let mut child = Command::new("git")
+            .current_dir(self.toplevel()?)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        child.stdin.take().unwrap().write_all(input.as_bytes())?;
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            bail!("git {} failed", args.join(" "));
         }
+        Ok(String::from_utf8(output.stdout)?.trim().to_string())
+    }
+
+    fn read_blob(&self, sha: &str) -> Result<String> {
+        Ok(self.cmd_out(["cat-file", "-p", sha])?.join("\n"))
+    }
 
+    ///Open `path` in the editor git is configured to use and wait until it is closed
+    fn run_editor(&self, path: &Path) -> Result<()> {
+        let editor = self.cmd_out(["var", "GIT_EDITOR"])?.join("");
+        let status = Command::new("sh")
+            .args(["-c", format!("{} \"$@\"", editor).as_str(), "editor"])
+            .arg(path)
+            .status()?;
+        if !status.success() {
+            bail!("Editor {} exited with {}", editor, status);
+        }
         Ok(())
     }
 
-    fn head_sha(&self, branch_name: &String) -> Result<String> {
-        let output = self.cmd_out(&["rev-parse", branch_name])?;
-        Ok(output.first().unwrap().clone())
+    ///Top level directory of the worktree the repository was opened in
+    fn toplevel(&self) -> Result<PathBuf> {
+        let output = self.cmd_out(["rev-parse", "--path-format=absolute", "--show-toplevel"])?;
+        Ok(PathBuf::from(output.join("")))
+    }
+
+    ///Paths of the main worktree and all linked worktrees
+    fn worktree_paths(&self) -> Result<Vec<PathBuf>> {
+        Ok(self
+            .cmd_out(["worktree", "list", "--porcelain"])?
+            .iter()
+            .filter_map(|line| line.strip_prefix("worktree "))
+            .map(PathBuf::from)
+            .collect())
+    }
+
+    ///Branches that are checked out in any worktree of the repository, with the worktree's path
+    fn worktrees(&self) -> Result<BTreeMap<String, PathBuf>> {
+        let mut worktrees = BTreeMap::new();
+        let mut path = None;
+        for line in self.cmd_out(["worktree", "list", "--porcelain"])? {
+            if let Some(worktree) = line.strip_prefix("worktree ") {
+                path = Some(PathBuf::from(worktree));
+            } else if let Some(branch) = line.strip_prefix("branch refs/heads/") {
+                if let Some(path) = &path {
+                    worktrees.insert(branch.to_string(), path.clone());
+                }
+            }
+        }
+        Ok(worktrees)
+    }
+
+    ///Path of the worktree `branch` is checked out in, if that is not the worktree of this repository
+    fn other_worktree(&self, branch: &str) -> Result<Option<PathBuf>> {
+        let toplevel = self.toplevel()?;
+        Ok(self
+            .worktrees()?
+            .remove(branch)
+            .filter(|path| *path != toplevel))
+    }
+
+    fn add_worktree(&self, branch: &str, path: &Path) -> Result<()> {
+        let path = path.to_string_lossy();
+        self.cmd(["worktree", "add", path.as_ref(), branch])?;
+        Ok(())
+    }
+}
+
+/// Finds the repository containing `path`, returning it rooted at the top level of its
+/// (work)tree together with the git common dir that all worktrees share
+pub fn open_repository(path: &Path) -> Result<(Repository, PathBuf)> {
+    let output = Command::new("git")
+        .current_dir(path)
+        .args([
+            "rev-parse",
+            "--path-format=absolute",
+            "--show-toplevel",
+            "--git-common-dir",
+        ])
+        .output()?;
+    if !output.status.success() {
+        bail!("{} is not inside a git repository", path.display());
+    }
+    let output = String::from_utf8(output.stdout)?;
+    let mut lines = output.lines();
+    let (Some(toplevel), Some(common_dir)) = (lines.next(), lines.next()) else {
+        bail!(
+            "Could not determine the git directory of {}",
+            path.display()
+        );
+    };
+    Ok((
+        Repository::new(PathBuf::from(toplevel)),
+        PathBuf::from(common_dir),
+    ))
+}
+
+/// Turns a failed rebase into a [`RebaseConflict`] if it stopped because of conflicts
+fn rebase_error(repo: &Repository, branch: &str, err: GitError) -> anyhow::Error {
+    if !repo.is_rebase_in_progress().unwrap_or(false) {
+        return err.into();
+    }
+    let files = repo
+        .cmd_out(["diff", "--name-only", "--diff-filter=U"])
+        .unwrap_or_default();
+    RebaseConflict {
+        branch: branch.to_string(),
+        files,
+        worktree: repo.toplevel().unwrap_or_default(),
     }
+    .into()
 }
@@ -0,0 +1,181 @@
+use std::collections::BTreeMap;
+
+use anyhow::Result;
+use rustygit::Repository;
+
+use crate::{
+    migrations::{self, CURRENT_VERSION},
+    repo_extensions::RepoExtenstions,
+    state::{GitStack, GsState},
+};
+
+/// Last shared version of each stack keyed by its id, pushed to the remote by `gs sync`
+const SHARED_REFS: &str = "refs/gstack/shared";
+/// Versions of the shared stacks as last fetched from the remote
+const REMOTE_REFS: &str = "refs/gstack/remote-shared";
+const STACK_FILE: &str = "stack.ron";
+
+/// Refspec that fetches the shared stacks of the remote
+pub fn fetch_refspec() -> String {
+    format!("+{}/*:{}/*", SHARED_REFS, REMOTE_REFS)
+}
+
+pub fn push(repo: &Repository, remote: &str, id: &str) -> Result<()> {
+    let shared_ref = format!("{}/{}", SHARED_REFS, id);
+    let refspec = format!("{}:{}", shared_ref, shared_ref);
+    repo.cmd(["push", remote, refspec.as_str()])?;
+    Ok(())
+}
+
+/// Ids and commits of all fetched stacks
+pub fn list_remote(repo: &Repository) -> Result<Vec<(String, String)>> {
+    let prefix = format!("{}/", REMOTE_REFS);
+    let refs = repo.cmd_out([
+        "for-each-ref",
+        "--format=%(refname) %(objectname)",
+        prefix.as_str(),
+    ])?;
+    Ok(refs
+        .iter()
+        .filter_map(|line| line.split_once(' '))
+        .map(|(name, sha)| {
+            (
+                name.trim_start_matches(&prefix).to_string(),
+                sha.to_string(),
+            )
+        })
+        .collect())
+}
+
+pub fn read(repo: &Repository, commit: &str) -> Result<GitStack> {
+    let contents = repo.cmd_out(["show", format!("{}:{}", commit, STACK_FILE).as_str()])?;
+    migrations::parse_shared_stack(ron::from_str(&contents.join("\n"))?)
+}
+
+/// Commits the stack on top of its last shared version (and `merged`, if given), unless
+/// nothing changed. Returns the commit the shared ref of the stack points to afterwards.
+pub fn write(repo: &Repository, stack: &GitStack, merged: Option<&str>) -> Result<String> {
+    let shared_ref = format!("{}/{}", SHARED_REFS, stack.id);
+    let previous = repo.head_sha(&shared_ref).ok();
+    // Stored like a state with a single stack so it goes through the same migrations
+    let state = GsState {
+        version: CURRENT_VERSION,
+        stacks: vec![stack.clone()],
+        remote_heads: BTreeMap::new(),
+    };
+    let blob = repo.write_blob(&ron::ser::to_string_pretty(
+        &state,
+        ron::ser::PrettyConfig::default(),
+    )?)?;
+    let tree = repo.cmd_with_input(
+        &["mktree"],
+        &format!("100644 blob {}\t{}\n", blob, STACK_FILE),
+    )?;
+    if let Some(previous) = &previous {
+        let previous_tree = repo.head_sha(&format!("{}^{{tree}}", previous))?;
+        if previous_tree == tree && merged.is_none() {
+            return Ok(previous.clone());
+        }
+    }
+
+    let mut args = vec!["commit-tree", tree.as_str(), "-m", "Update stack"];
+    for parent in previous.iter().map(|p| p.as_str()).chain(merged) {
+        args.extend(["-p", parent]);
+    }
+    let commit = repo.cmd_out(args)?.join("");
+    repo.cmd(["update-ref", shared_ref.as_str(), commit.as_str()])?;
+    Ok(commit)
+}
+
+/// Points the shared ref of a stack at a fetched version
+pub fn fast_forward(repo: &Repository, id: &str, commit: &str) -> Result<()> {
+    let shared_ref = format!("{}/{}", SHARED_REFS, id);
+    repo.cmd(["update-ref", shared_ref.as_str(), commit])?;
+    Ok(())
+}
+
+/// Combines two diverged versions of a stack. Branches only known to the remote are added
+/// on top, for everything else the local version wins.
+pub fn merge(local: &GitStack, remote: &GitStack) -> GitStack {
+    let mut merged = local.clone();
+    for branch in &remote.branches {
+        if merged.branches.contains(branch) {
+            continue;
+        }
+        merged.branches.push(branch.clone());
+        if let Some(parent) = remote.parent(branch) {
+            merged.parents.insert(branch.clone(), parent.clone());
+        }
+        if let Some(fork_point) = remote.fork_points.get(branch) {
+            merged
+                .fork_points
+                .insert(branch.clone(), fork_point.clone());
+        }
+    }
+    for (branch, pr) in &remote.pull_requests {
+        if merged.branches.contains(branch) {
+            merged
+                .pull_requests
+                .entry(branch.clone())
+                .or_insert_with(|| pr.clone());
+        }
+    }
+    merged
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::*;
+    use crate::state::StackPullRequest;
+
+    fn stack(parents: &[(&str, &str)], pull_requests: &[(&str, u64)]) -> GitStack {
+        GitStack {
+            id: parents[0].0.to_string(),
+            base_branch: "main".to_string(),
+            branches: parents.iter().map(|(b, _)| b.to_string()).collect(),
+            parents: parents
+                .iter()
+                .map(|(b, p)| (b.to_string(), p.to_string()))
+                .collect(),
+            pull_requests: pull_requests
+                .iter()
+                .map(|(b, number)| {
+                    let pr = StackPullRequest {
+                        number: *number,
+                        url: String::new(),
+                        head_sha: String::new(),
+                    };
+                    (b.to_string(), pr)
+                })
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn merge_keeps_local_changes() {
+        let local = stack(&[("a", "main"), ("b", "a")], &[("a", 1)]);
+        let remote = stack(&[("a", "main"), ("b", "main")], &[("a", 5), ("b", 2)]);
+        let merged = merge(&local, &remote);
+        assert_eq!(merged.branches, ["a", "b"]);
+        assert_eq!(merged.parent("b"), Some(&"a".to_string()));
+        assert_eq!(merged.pull_requests["a"].number, 1);
+        // PR's only known to the remote are added for branches of the merged stack
+        assert_eq!(merged.pull_requests["b"].number, 2);
+    }
+
+    #[test]
+    fn merge_adds_remote_branches() {
+        let local = stack(&[("a", "main")], &[]);
+        let mut remote = stack(&[("a", "main"), ("c", "a")], &[("c", 3), ("gone", 4)]);
+        remote.fork_points = BTreeMap::from([("c".to_string(), "sha".to_string())]);
+        let merged = merge(&local, &remote);
+        assert_eq!(merged.branches, ["a", "c"]);
+        assert_eq!(merged.parent("c"), Some(&"a".to_string()));
+        assert_eq!(merged.fork_points["c"], "sha");
+        assert_eq!(merged.pull_requests["c"].number, 3);
+        assert!(!merged.pull_requests.contains_key("gone"));
+    }
+}
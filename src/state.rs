@@ -1,44 +1,301 @@
 use std::{
-    fs::{self, File},
-    io::Read,
-    path::PathBuf,
+    collections::BTreeMap,
+    fs::{self, File, OpenOptions, TryLockError},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
 };
 
-use anyhow::Result;
+use anyhow::{anyhow, Context, Result};
+use console::style;
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize, Default, Debug)]
+use crate::migrations::{self, CURRENT_VERSION};
+
+const STATE_FILE: &str = "state.ron";
+const LOCK_FILE: &str = "lock";
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct StackPullRequest {
+    pub number: u64,
+    pub url: String,
+    pub head_sha: String,
+}
+
+#[derive(Serialize, Deserialize, Default, Clone, Debug)]
 pub struct GitStack {
+    /// Identifies the stack when it is shared, the name of its first branch when it was created
+    pub id: String,
     pub prefix: Option<String>,
     pub base_branch: String,
+    /// All branches of the stack, every branch comes after its parent
     pub branches: Vec<String>,
+    /// Parent of each branch, either another stack branch or the base branch
+    pub parents: BTreeMap<String, String>,
+    /// PR's that were created or discovered for the stack branches, keyed by branch name
+    pub pull_requests: BTreeMap<String, StackPullRequest>,
+    /// Head of the parent branch each branch was built on or last rebased onto, keyed by branch name
+    pub fork_points: BTreeMap<String, String>,
+}
+
+impl GitStack {
+    /// Prefix of the stack, or its first branch if it has no prefix
+    pub fn name(&self) -> String {
+        match &self.prefix {
+            Some(prefix) => prefix.clone(),
+            None => self.branches.first().cloned().unwrap_or_default(),
+        }
+    }
+
+    pub fn parent(&self, branch: &str) -> Option<&String> {
+        self.parents.get(branch)
+    }
+
+    /// Stack branches that have `branch` as their parent, pass the base branch to get the roots
+    pub fn children(&self, branch: &str) -> Vec<String> {
+        self.branches
+            .iter()
+            .filter(|b| self.parent(b).is_some_and(|parent| parent == branch))
+            .cloned()
+            .collect()
+    }
+
+    /// The branch followed by all branches stacked on top of it, in stack order
+    pub fn upstack(&self, branch: &str) -> Vec<String> {
+        let mut upstack = vec![branch.to_string()];
+        for b in &self.branches {
+            if self
+                .parent(b)
+                .is_some_and(|parent| upstack.contains(parent))
+            {
+                upstack.push(b.clone());
+            }
+        }
+        upstack
+    }
+
+    /// Whether every branch is stacked on the branch before it
+    pub fn is_linear(&self) -> bool {
+        self.branches.iter().enumerate().all(|(i, branch)| {
+            let expected = match i {
+                0 => &self.base_branch,
+                _ => &self.branches[i - 1],
+            };
+            self.parent(branch) == Some(expected)
+        })
+    }
+
+    /// Removes a branch, its children are stacked onto its parent instead
+    pub fn remove_branch(&mut self, branch: &str) {
+        let parent = self
+            .parents
+            .remove(branch)
+            .unwrap_or(self.base_branch.clone());
+        for child in self.children(branch) {
+            self.parents.insert(child, parent.clone());
+        }
+        self.branches.retain(|b| b != branch);
+        self.pull_requests.remove(branch);
+        self.fork_points.remove(branch);
+    }
+
+    /// Stacks every branch without a parent on the branch before it
+    pub fn record_linear_parents(&mut self) {
+        for (i, branch) in self.branches.iter().enumerate() {
+            let parent = match i {
+                0 => &self.base_branch,
+                _ => &self.branches[i - 1],
+            };
+            self.parents
+                .entry(branch.clone())
+                .or_insert_with(|| parent.clone());
+        }
+    }
 }
 
-#[derive(Serialize, Deserialize, Default, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct GsState {
+    /// Version of the state format, older states are upgraded by [`migrations`]
+    pub version: u32,
     pub stacks: Vec<GitStack>,
+    /// Head of each branch on the remote as gstack last fetched or pushed it, used to tell
+    /// local rewrites apart from commits someone else pushed
+    pub remote_heads: BTreeMap<String, String>,
+}
+
+impl Default for GsState {
+    fn default() -> Self {
+        GsState {
+            version: CURRENT_VERSION,
+            stacks: vec![],
+            remote_heads: BTreeMap::new(),
+        }
+    }
 }
 
 impl GsState {
-    pub fn init(base_path: PathBuf) -> Result<GsState> {
-        let state = match File::open(base_path.clone().join(".git/gstack/state.ron")) {
-            Ok(mut file) => {
-                let mut contents = String::new();
-                file.read_to_string(&mut contents)?;
-                ron::from_str(&contents)?
-            }
-            Err(_) => {
-                fs::create_dir(base_path.clone().join(".git/gstack"));
-                GsState::default()
+    /// Reads the state from the gstack directory inside the git common dir, which is shared
+    /// by all worktrees of the repository
+    pub fn init(gstack_dir: PathBuf) -> Result<GsState> {
+        let path = gstack_dir.join(STATE_FILE);
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(GsState::default()),
+            Err(err) => {
+                return Err(anyhow!(err).context(format!("Could not read {}", path.display())))
             }
         };
+        let value = ron::from_str(&contents).map_err(|err| {
+            anyhow!(
+                "Could not parse {}: {}\nFix the file or move it away to start without any stacks.",
+                path.display(),
+                err
+            )
+        })?;
+        let (state, version) = migrations::parse_state(value)
+            .with_context(|| format!("Could not read {}", path.display()))?;
+        if version < CURRENT_VERSION {
+            let backup_path = gstack_dir.join(format!("{}.v{}.bak", STATE_FILE, version));
+            fs::copy(&path, &backup_path)
+                .with_context(|| format!("Could not back up {}", path.display()))?;
+            state.write(gstack_dir)?;
+            eprintln!(
+                "Upgraded the gstack state from version {} to {}, the previous state was saved to {}",
+                version,
+                CURRENT_VERSION,
+                backup_path.display()
+            );
+        }
         Ok(state)
     }
 
-    pub fn write(&self, base_path: PathBuf) -> Result<()> {
-        let string_value =
-            ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default()).unwrap();
-        fs::write(base_path.join(".git/gstack/state.ron"), string_value)?;
-        Ok(())
+    pub fn write(&self, gstack_dir: PathBuf) -> Result<()> {
+        let string_value = ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())?;
+        write_file(&gstack_dir.join(STATE_FILE), &string_value)
+    }
+}
+
+/// Replaces a file by writing a temporary file next to it and renaming it, so that a crash
+/// never leaves a partially written file behind
+pub fn write_file(path: &Path, contents: &str) -> Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    // The contents have to be on disk before the rename, or a crash could leave an empty file
+    let mut file = File::create(&tmp_path)
+        .with_context(|| format!("Could not write {}", tmp_path.display()))?;
+    file.write_all(contents.as_bytes())
+        .and_then(|_| file.sync_all())
+        .with_context(|| format!("Could not write {}", tmp_path.display()))?;
+    fs::rename(&tmp_path, path).with_context(|| format!("Could not replace {}", path.display()))?;
+    Ok(())
+}
+
+/// Advisory lock on the gstack directory that keeps concurrent `gs` invocations from
+/// overwriting each other's state, released when dropped
+pub struct StateLock {
+    _file: File,
+}
+
+impl StateLock {
+    /// Creates the gstack directory if needed and waits until no other `gs` command holds the lock
+    pub fn acquire(gstack_dir: &Path) -> Result<StateLock> {
+        fs::create_dir_all(gstack_dir)
+            .with_context(|| format!("Could not create {}", gstack_dir.display()))?;
+        let path = gstack_dir.join(LOCK_FILE);
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)
+            .with_context(|| format!("Could not open {}", path.display()))?;
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => {
+                eprintln!(
+                    "{}",
+                    style("Waiting for another gs command in this repository to finish...").dim()
+                );
+                file.lock()
+                    .with_context(|| format!("Could not lock {}", path.display()))?;
+            }
+            Err(TryLockError::Error(err)) => {
+                return Err(anyhow!(err).context(format!("Could not lock {}", path.display())))
+            }
+        }
+        Ok(StateLock { _file: file })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A stack on `main` with the given branches and parents, in stack order
+    fn stack(parents: &[(&str, &str)]) -> GitStack {
+        GitStack {
+            id: parents[0].0.to_string(),
+            base_branch: "main".to_string(),
+            branches: parents.iter().map(|(b, _)| b.to_string()).collect(),
+            parents: parents
+                .iter()
+                .map(|(b, p)| (b.to_string(), p.to_string()))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    fn pull_request(number: u64) -> StackPullRequest {
+        StackPullRequest {
+            number,
+            url: String::new(),
+            head_sha: String::new(),
+        }
+    }
+
+    #[test]
+    fn upstack_of_tree() {
+        let stack = stack(&[
+            ("a", "main"),
+            ("b", "a"),
+            ("c", "main"),
+            ("d", "b"),
+            ("e", "a"),
+        ]);
+        assert_eq!(stack.upstack("a"), ["a", "b", "d", "e"]);
+        assert_eq!(stack.upstack("c"), ["c"]);
+        assert_eq!(stack.children("a"), ["b", "e"]);
+        assert_eq!(stack.children("main"), ["a", "c"]);
+        assert!(!stack.is_linear());
+    }
+
+    #[test]
+    fn linear_stack() {
+        let stack = stack(&[("a", "main"), ("b", "a"), ("c", "b")]);
+        assert!(stack.is_linear());
+        assert_eq!(stack.upstack("b"), ["b", "c"]);
+    }
+
+    #[test]
+    fn remove_branch_reparents_children() {
+        let mut stack = stack(&[("a", "main"), ("b", "a"), ("c", "b"), ("d", "b")]);
+        stack.pull_requests.insert("b".to_string(), pull_request(2));
+        stack.fork_points.insert("b".to_string(), "sha".to_string());
+        stack.remove_branch("b");
+        assert_eq!(stack.branches, ["a", "c", "d"]);
+        assert_eq!(stack.parent("c"), Some(&"a".to_string()));
+        assert_eq!(stack.parent("d"), Some(&"a".to_string()));
+        assert!(stack.parent("b").is_none());
+        assert!(stack.pull_requests.is_empty());
+        assert!(stack.fork_points.is_empty());
+
+        stack.remove_branch("a");
+        assert_eq!(stack.children("main"), ["c", "d"]);
+    }
+
+    #[test]
+    fn record_linear_parents_keeps_known_parents() {
+        let mut stack = stack(&[("a", "main"), ("c", "main")]);
+        stack.branches.insert(1, "b".to_string());
+        stack.record_linear_parents();
+        assert_eq!(stack.parent("b"), Some(&"a".to_string()));
+        assert_eq!(stack.parent("c"), Some(&"main".to_string()));
     }
 }